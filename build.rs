@@ -1,4 +1,16 @@
+use std::env;
+
 fn main() {
-    println!("cargo:rustc-link-search=/home/ahomolya/Developer/jerasure/install/lib");
-    println!("cargo:rustc-link-search=/home/ahomolya/Developer/gf-complete/install/lib")
+    if env::var_os("CARGO_FEATURE_JERASURE").is_none() {
+        return;
+    }
+
+    println!("cargo:rerun-if-env-changed=JERASURE_LIB_DIR");
+    println!("cargo:rerun-if-env-changed=GF_COMPLETE_LIB_DIR");
+
+    for variable in ["JERASURE_LIB_DIR", "GF_COMPLETE_LIB_DIR"].iter() {
+        if let Some(path) = env::var_os(variable) {
+            println!("cargo:rustc-link-search={}", path.to_string_lossy());
+        }
+    }
 }
@@ -0,0 +1,268 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use rand;
+use rand::Rng;
+
+use jerasurs::{ErasureCodec, Geometry, Technique};
+use jerasurs::registry;
+
+static USAGE: &'static str = "\
+usage: distriraid bench [options]
+
+Measures encode and decode throughput of every valid combination of the parameters below. Lists
+are comma separated.
+
+    --technique LIST     codecs, e.g. reed_sol_van,lrc (default: all)
+    --k LIST             data block counts (default: 1,4,6,10)
+    --m LIST             parity block counts (default: 2,3,4)
+    --w LIST             word sizes (default: 7,8,10,16,32)
+    --packet-size LIST   packet sizes of the bit matrix codecs (default: 64,1024)
+    --block-size LIST    block sizes in bytes (default: 65536,1048576)
+    --seconds SECONDS    minimum time spent on a single measurement (default: 0.2)
+    --json               print one JSON object per measurement";
+
+struct Options {
+    techniques: Vec<Technique>,
+    data_block_counts: Vec<u32>,
+    parity_block_counts: Vec<u32>,
+    word_sizes: Vec<u32>,
+    packet_sizes: Vec<usize>,
+    block_sizes: Vec<usize>,
+    duration: Duration,
+    json: bool
+}
+
+struct Measurement {
+    codec: &'static str,
+    geometry: Geometry,
+    block_size: usize,
+    operation: &'static str,
+    erasures: usize,
+    pattern: &'static str,
+    throughput: f64
+}
+
+impl Measurement {
+    fn to_text(&self) -> String {
+        format!("{:<12} k={:<3} m={:<3} w={:<3} packet={:<6} block={:<9} {:<6} erasures={:<3} \
+                 {:<6} {:>8.3} GB/s",
+                self.codec,
+                self.geometry.data_block_count, self.geometry.parity_block_count,
+                self.geometry.word_size, self.geometry.packet_size,
+                self.block_size, self.operation, self.erasures, self.pattern, self.throughput)
+    }
+
+    fn to_json(&self) -> String {
+        format!("{{\"codec\":\"{}\",\"k\":{},\"m\":{},\"w\":{},\"packet_size\":{},\
+                 \"block_size\":{},\"operation\":\"{}\",\"erasures\":{},\"pattern\":\"{}\",\
+                 \"gb_per_s\":{:.6}}}",
+                self.codec,
+                self.geometry.data_block_count, self.geometry.parity_block_count,
+                self.geometry.word_size, self.geometry.packet_size,
+                self.block_size, self.operation, self.erasures, self.pattern, self.throughput)
+    }
+}
+
+fn parse_list<T: FromStr>(value: &str) -> Result<Vec<T>, String> {
+    value.split(',')
+        .map(|item| item.trim().parse::<T>().map_err(|_| format!("invalid value: {}", item)))
+        .collect()
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        techniques: Technique::all().to_vec(),
+        data_block_counts: vec![1, 4, 6, 10],
+        parity_block_counts: vec![2, 3, 4],
+        word_sizes: vec![7, 8, 10, 16, 32],
+        packet_sizes: vec![64, 1024],
+        block_sizes: vec![65536, 1048576],
+        duration: Duration::from_millis(200),
+        json: false
+    };
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--json" {
+            options.json = true;
+            continue;
+        }
+
+        let value = args.next().ok_or(format!("missing value of {}", arg))?;
+
+        match arg.as_str() {
+            "--technique" => {
+                options.techniques = value.split(',')
+                    .map(|name| Technique::from_name(name.trim())
+                        .ok_or(format!("unknown technique: {}", name)))
+                    .collect::<Result<Vec<_>, _>>()?;
+            },
+            "--k" => options.data_block_counts = parse_list(value)?,
+            "--m" => options.parity_block_counts = parse_list(value)?,
+            "--w" => options.word_sizes = parse_list(value)?,
+            "--packet-size" => options.packet_sizes = parse_list(value)?,
+            "--block-size" => options.block_sizes = parse_list(value)?,
+            "--seconds" => {
+                let seconds = value.parse::<f64>()
+                    .map_err(|_| format!("invalid value: {}", value))?;
+                options.duration = Duration::from_millis((seconds * 1000.0) as u64);
+            },
+            _ => return Err(format!("unknown option: {}", arg))
+        }
+    }
+
+    Ok(options)
+}
+
+// Runs f until the measurement took at least duration, and returns the throughput in GB/s. f
+// returns the time spent on the measured part only.
+fn throughput<F: FnMut() -> Duration>(bytes: usize, duration: Duration, mut f: F) -> f64 {
+    let mut elapsed = Duration::from_secs(0);
+    let mut iterations = 0u64;
+
+    while elapsed < duration || iterations == 0 {
+        elapsed += f();
+        iterations += 1;
+    }
+
+    (bytes as f64 * iterations as f64) / elapsed.as_secs_f64() / 1e9
+}
+
+// Representative choices of the erased blocks: the first data blocks, the first parity blocks, and
+// data and parity blocks half and half. Patterns that don't fit the geometry are left out.
+fn erasure_patterns(geometry: &Geometry, erasures: usize) -> Vec<(&'static str, Vec<usize>)> {
+    let k = geometry.data_block_count;
+    let mixed_data = erasures - erasures / 2;
+    let mut patterns = Vec::new();
+
+    if erasures <= k {
+        patterns.push(("data", (0..erasures).collect()));
+    }
+
+    if erasures <= geometry.parity_block_count {
+        patterns.push(("parity", (k..k + erasures).collect()));
+    }
+
+    if erasures >= 2 && mixed_data <= k {
+        patterns.push(("mixed", (0..mixed_data).chain(k..k + erasures / 2).collect()));
+    }
+
+    patterns
+}
+
+fn measure(codec: &dyn ErasureCodec, block_size: usize,
+           options: &Options) -> Result<Vec<Measurement>, String> {
+
+    let geometry = codec.geometry();
+
+    // Round the object up to whole stripes, so every block is exactly block_size bytes
+    let packet_alignment = geometry.chunk_size / geometry.data_block_count;
+    let block_size = (block_size + packet_alignment - 1) / packet_alignment * packet_alignment;
+    let data_size = block_size * geometry.data_block_count;
+
+    let input = rand::thread_rng().gen_iter::<u8>().take(data_size).collect::<Vec<_>>();
+    let encoded = codec.encode(&input).map_err(|error| error.to_string())?;
+
+    let mut result = Vec::new();
+
+    result.push(Measurement {
+        codec: codec.name(),
+        geometry: geometry,
+        block_size: block_size,
+        operation: "encode",
+        erasures: 0,
+        pattern: "none",
+        throughput: throughput(data_size, options.duration, || {
+            let start = Instant::now();
+            codec.encode(&input).expect("encoding worked before");
+            start.elapsed()
+        })
+    });
+
+    // Losing data blocks is the expensive case, parity blocks are simply re-encoded
+    for erasures in 1..geometry.parity_block_count + 1 {
+        for (pattern, ids) in erasure_patterns(&geometry, erasures) {
+            let mut failure = None;
+
+            let throughput = throughput(data_size, options.duration, || {
+                let mut buffer = encoded.clone();
+
+                for id in ids.iter() {
+                    buffer.erase_block(*id, true).expect("erasures fit into the stripe");
+                }
+
+                let start = Instant::now();
+
+                if let Err(error) = codec.decode(&mut buffer) {
+                    failure = Some(error);
+                }
+
+                start.elapsed()
+            });
+
+            // Codecs like LRC can't restore every erasure pattern up to m
+            if failure.is_some() {
+                continue;
+            }
+
+            result.push(Measurement {
+                codec: codec.name(),
+                geometry: geometry,
+                block_size: block_size,
+                operation: "decode",
+                erasures: erasures,
+                pattern: pattern,
+                throughput: throughput
+            });
+        }
+    }
+
+    Ok(result)
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let options = parse_options(args).map_err(|error| format!("{}\n\n{}", error, USAGE))?;
+
+    // Matrix codecs ignore the packet size, those geometries are only measured once
+    let mut measured = HashSet::new();
+
+    for &technique in options.techniques.iter() {
+        for &k in options.data_block_counts.iter() {
+            for &m in options.parity_block_counts.iter() {
+                for &w in options.word_sizes.iter() {
+                    for &packet_size in options.packet_sizes.iter() {
+                        let codec = match registry::codec(technique, k, m, w, packet_size) {
+                            Ok(codec) => codec,
+                            Err(_) => continue
+                        };
+
+                        let geometry = codec.geometry();
+
+                        if !measured.insert((technique, k, m, w, geometry.packet_size)) {
+                            continue;
+                        }
+
+                        for &block_size in options.block_sizes.iter() {
+                            for measurement in measure(&*codec, block_size, &options)? {
+                                match options.json {
+                                    true => println!("{}", measurement.to_json()),
+                                    _    => println!("{}", measurement.to_text())
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use std::io;
 use std::io::{Read, Write, Cursor};
 use std::vec::Vec;
 use std::result;
@@ -5,10 +6,22 @@ use std::iter::{DoubleEndedIterator, Iterator};
 
 use super::device;
 use super::device::StorageDevice;
-use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-static CHUNK_HEADER_SIZE: usize = 3 * 8 + 1; // 3 * size_of::<u64>() + size_of::<u8>();
+// Chunks are laid out little-endian, whatever the architecture:
+//
+//   header   data size u64, allocated u8, previous free chunk u64, next free chunk u64
+//   data     data size bytes
+//   footer   data size u64, allocated u8
+//
+// Devices written before this layout used the native byte order, the superblock version tells
+// them apart.
+pub static CHUNK_HEADER_SIZE: usize = 3 * 8 + 1; // 3 * size_of::<u64>() + size_of::<u8>();
 static CHUNK_FOOTER_SIZE: usize = 8 + 1;     // size_of::<u64>() + size_of::<u8>();
+pub static CHUNK_OVERHEAD: usize = CHUNK_HEADER_SIZE + CHUNK_FOOTER_SIZE;
+
+// Splitting off anything smaller only fragments the heap
+static MIN_CHUNK_DATA_SIZE: usize = 64;
 
 #[derive(Clone)]
 struct ChunkHeader {
@@ -39,34 +52,34 @@ pub struct FreeList<'a> {
 pub type Buffer = Cursor<Vec<u8>>;
 
 impl ChunkHeader {
-    fn load(reader: &mut Read) -> ChunkHeader {
-        ChunkHeader {
-            data_size: reader.read_u64::<NativeEndian>().unwrap() as usize,
-            allocated: reader.read_u8().unwrap() == 1,
-            prev_free: reader.read_u64::<NativeEndian>().unwrap(),
-            next_free: reader.read_u64::<NativeEndian>().unwrap()
-        }
+    fn load(reader: &mut Read) -> io::Result<ChunkHeader> {
+        Ok(ChunkHeader {
+            data_size: reader.read_u64::<LittleEndian>()? as usize,
+            allocated: reader.read_u8()? == 1,
+            prev_free: reader.read_u64::<LittleEndian>()?,
+            next_free: reader.read_u64::<LittleEndian>()?
+        })
     }
 
-    fn dump(header: &ChunkHeader, writer: &mut Write) {
-        writer.write_u64::<NativeEndian>(header.data_size as u64);
-        writer.write_u8(header.allocated as u8);
-        writer.write_u64::<NativeEndian>(header.prev_free as u64);
-        writer.write_u64::<NativeEndian>(header.next_free as u64);
+    fn dump(header: &ChunkHeader, writer: &mut Write) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(header.data_size as u64)?;
+        writer.write_u8(header.allocated as u8)?;
+        writer.write_u64::<LittleEndian>(header.prev_free)?;
+        writer.write_u64::<LittleEndian>(header.next_free)
     }
 }
 
 impl ChunkFooter {
-    fn load(reader: &mut Read) -> ChunkFooter {
-        ChunkFooter {
-            data_size: reader.read_u64::<NativeEndian>().unwrap() as usize,
-            allocated: reader.read_u8().unwrap() == 1
-        }
+    fn load(reader: &mut Read) -> io::Result<ChunkFooter> {
+        Ok(ChunkFooter {
+            data_size: reader.read_u64::<LittleEndian>()? as usize,
+            allocated: reader.read_u8()? == 1
+        })
     }
 
-    fn dump(footer: &ChunkFooter, writer: &mut Write) {
-        writer.write_u64::<NativeEndian>(footer.data_size as u64);
-        writer.write_u8(footer.allocated as u8);
+    fn dump(footer: &ChunkFooter, writer: &mut Write) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(footer.data_size as u64)?;
+        writer.write_u8(footer.allocated as u8)
     }
 }
 
@@ -76,52 +89,157 @@ impl Chunk {
         device.read_at(offset, buffer.as_mut_slice()).map(|_| Cursor::new(buffer))
     }
 
-    pub fn remove_from_freelist(&mut self, device: &mut StorageDevice) -> device::Result<()> {
+    // Unlinks the chunk from its free list and marks it allocated. Every list starts with a
+    // sentinel head, so a listed chunk always has a predecessor.
+    pub fn remove_from_freelist(&mut self, device: &StorageDevice) -> device::Result<()> {
         let mut prev = Self::load_from_device(device, self.header.prev_free)?;
-        let mut next = Self::load_from_device(device, self.header.next_free)?;
+        prev.header.next_free = self.header.next_free;
+        prev.dump_to_device(device)?;
 
-        next.header.prev_free = prev.offset;
-        prev.header.next_free = next.offset;
+        if self.header.next_free != 0 {
+            let mut next = Self::load_from_device(device, self.header.next_free)?;
+            next.header.prev_free = prev.offset;
+            next.dump_to_device(device)?;
+        }
 
         self.header.allocated = true;
+        self.header.prev_free = 0;
+        self.header.next_free = 0;
+
+        self.dump_to_device(device)
+    }
+
+    // Links the chunk right after the sentinel head at head_offset and marks it free
+    pub fn insert_into_freelist(&mut self, head_offset: u64, device: &StorageDevice)
+        -> device::Result<()> {
+
+        let mut head = Self::load_from_device(device, head_offset)?;
+
+        if head.header.next_free != 0 {
+            let mut next = Self::load_from_device(device, head.header.next_free)?;
+            next.header.prev_free = self.offset;
+            next.dump_to_device(device)?;
+        }
 
+        self.header.allocated = false;
+        self.header.prev_free = head.offset;
+        self.header.next_free = head.header.next_free;
         self.dump_to_device(device)?;
-        prev.dump_to_device(device)?;
-        next.dump_to_device(device)?;
 
-        Ok(())
+        head.header.next_free = self.offset;
+        head.dump_to_device(device)
     }
 
-    pub fn coalesce_two(mut first: Chunk, mut second: Chunk, device: &mut StorageDevice) -> device::Result<Chunk> {
+    // Merges two adjacent chunks. Free chunks are unlinked first, the result is marked allocated
+    // and it's up to the caller to put it back on a free list.
+    pub fn coalesce_two(mut first: Chunk, second: Chunk, device: &StorageDevice)
+        -> device::Result<Chunk> {
+
         if !first.allocated() {
-            let mut prev = Self::load_from_device(device, second.header.prev_free)?;
-            let mut next = Self::load_from_device(device, second.header.next_free)?;
+            first.remove_from_freelist(device)?;
+        }
 
-            prev.header.next = first.offset;
-            next.header.prev = first.offset;
+        // Unlinking first may have rewritten the list pointers of second
+        let mut second = Self::load_from_device(device, second.offset)?;
+        if !second.allocated() {
+            second.remove_from_freelist(device)?;
+        }
 
-            first.header.data_size += second.data_size() + CHUNK_HEADER_SIZE;
-            first.dump_to_device(device)?;
+        let merged = Chunk {
+            offset: first.offset,
+            header: ChunkHeader {
+                data_size: first.data_size() + CHUNK_OVERHEAD + second.data_size(),
+                allocated: true,
+                prev_free: 0,
+                next_free: 0
+            }
+        };
 
-            Ok(first)
-        } else {
-            second.remove_from_freelist(device)?;
+        merged.dump_to_device(device)?;
+
+        Ok(merged)
+    }
 
+    pub fn coalesce_three(first: Chunk, second: Chunk, third: Chunk, device: &StorageDevice)
+        -> device::Result<Chunk> {
+
+        let merged = Self::coalesce_two(first, second, device)?;
+
+        // Unlinking first may have rewritten the list pointers of third
+        let third = Self::load_from_device(device, third.offset)?;
+        Self::coalesce_two(merged, third, device)
+    }
+
+    // Shrinks an allocated chunk to size and returns the rest as a new allocated chunk, if the
+    // rest is large enough to be worth keeping
+    pub fn split(&mut self, size: usize, device: &StorageDevice) -> device::Result<Option<Chunk>> {
+        if self.data_size() < size + CHUNK_OVERHEAD + MIN_CHUNK_DATA_SIZE {
+            return Ok(None);
         }
+
+        let rest = Chunk {
+            offset: self.data_offset() + size as u64 + CHUNK_FOOTER_SIZE as u64,
+            header: ChunkHeader {
+                data_size: self.data_size() - size - CHUNK_OVERHEAD,
+                allocated: true,
+                prev_free: 0,
+                next_free: 0
+            }
+        };
+
+        self.header.data_size = size;
+        self.dump_to_device(device)?;
+        rest.dump_to_device(device)?;
+
+        Ok(Some(rest))
     }
 
-    pub fn coalesce_three(first: Chunk, second: Chunk, third: Chunk) {
+    // Creates a chunk spanning size bytes in total, header and footer included
+    pub fn create(device: &StorageDevice, offset: u64, size: usize, allocated: bool)
+        -> device::Result<Chunk> {
 
+        let chunk = Chunk {
+            offset: offset,
+            header: ChunkHeader {
+                data_size: size - CHUNK_OVERHEAD,
+                allocated: allocated,
+                prev_free: 0,
+                next_free: 0
+            }
+        };
+
+        chunk.dump_to_device(device)?;
+
+        Ok(chunk)
+    }
+
+    // Checks the header against the boundary tag at the end of the chunk
+    pub fn is_consistent(&self, device: &StorageDevice) -> device::Result<bool> {
+        let footer_offset = self.data_offset() + self.data_size() as u64;
+
+        Self::_read_device(device, footer_offset, CHUNK_FOOTER_SIZE)
+            .and_then(|mut reader| Ok(ChunkFooter::load(&mut reader)?))
+            .map(|footer| {
+                footer.data_size == self.data_size() && footer.allocated == self.allocated()
+            })
     }
 
     pub fn load_from_device(device: &StorageDevice, offset: u64) -> device::Result<Chunk> {
         Self::_read_device(device, offset, CHUNK_HEADER_SIZE)
-            .map(|mut reader| Chunk { offset: offset, header: ChunkHeader::load(&mut reader) })
+            .and_then(|mut reader| Ok(ChunkHeader::load(&mut reader)?))
+            .map(|header| Chunk { offset: offset, header: header })
+    }
+
+    pub fn load_from_data_offset(device: &StorageDevice, data_offset: u64) -> device::Result<Chunk> {
+        match data_offset.checked_sub(CHUNK_HEADER_SIZE as u64) {
+            Some(offset) => Self::load_from_device(device, offset),
+            None => Err(device::DeviceError::InvalidOffset)
+        }
     }
 
-    pub fn dump_to_device(&self, device: &mut StorageDevice) -> device::Result<()> {
+    pub fn dump_to_device(&self, device: &StorageDevice) -> device::Result<()> {
         let mut writer = Cursor::new(vec![0u8; CHUNK_HEADER_SIZE]);
-        ChunkHeader::dump(&self.header, &mut writer);
+        ChunkHeader::dump(&self.header, &mut writer)?;
         device.write_at(self.offset, writer.get_ref().as_slice())?;
 
         let footer_offset = self.offset + CHUNK_HEADER_SIZE as u64 + self.data_size() as u64;
@@ -130,7 +248,7 @@ impl Chunk {
         ChunkFooter::dump(&ChunkFooter {
                 allocated: self.allocated(),
                 data_size: self.data_size()
-            }, &mut writer);
+            }, &mut writer)?;
 
         device.write_at(footer_offset, writer.get_ref().as_slice())?;
 
@@ -157,6 +275,11 @@ impl Chunk {
         self.header.data_size
     }
 
+    // Header, data and footer
+    pub fn size(&self) -> usize {
+        self.header.data_size + CHUNK_OVERHEAD
+    }
+
     pub fn unallocated_neighbours(&self, device: &StorageDevice)
         -> device::Result<(Option<Chunk>, Option<Chunk>)> {
 
@@ -182,7 +305,7 @@ impl Chunk {
     fn _peek_back(&self, device: &StorageDevice) -> device::Result<Chunk> {
         let footer_offset = self.offset - CHUNK_FOOTER_SIZE as u64;
         Chunk::_read_device(device, footer_offset, CHUNK_FOOTER_SIZE)
-            .map(|mut reader| ChunkFooter::load(&mut reader))
+            .and_then(|mut reader| Ok(ChunkFooter::load(&mut reader)?))
             .and_then(|footer| {
                 if footer.allocated {
                     return Ok(Chunk {
@@ -200,7 +323,7 @@ impl Chunk {
                     footer.data_size as u64 -
                     CHUNK_HEADER_SIZE as u64;
 
-                Chunk::load_from_device(device, self.offset)
+                Chunk::load_from_device(device, location)
             })
     }
 }
@@ -217,6 +340,14 @@ impl<'a> FreeList<'a> {
         &self.head
     }
 
+    pub fn iter(self) -> FreeListIterator<'a> {
+        FreeListIterator {
+            current: self,
+            failed: false,
+            first: true
+        }
+    }
+
     pub fn next(&self) -> Option<device::Result<FreeList<'a>>> {
         if self.head.header.next_free == 0 {
             None
@@ -362,4 +493,56 @@ impl<'a> DoubleEndedIterator for FreeListIterator<'a> {
 //            }
 //        }
 //    }
-//}
\ No newline at end of file
+//}
+#[cfg(test)]
+mod tests {
+    use super::{Chunk, CHUNK_HEADER_SIZE, CHUNK_OVERHEAD};
+    use super::super::device::memory::MemoryDevice;
+
+    #[test]
+    fn chunks_are_stored_little_endian() {
+        let device = MemoryDevice::new(4096, 4096);
+        let mut chunk = Chunk::create(&device, 0, 0x0102 + CHUNK_OVERHEAD, true).unwrap();
+        chunk.header.prev_free = 0x0a0b0c0d;
+        chunk.dump_to_device(&device).unwrap();
+
+        let header = &device.data()[0..CHUNK_HEADER_SIZE];
+        assert_eq!(header[0..9], [0x02, 0x01, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(header[9..17], [0x0d, 0x0c, 0x0b, 0x0a, 0, 0, 0, 0]);
+
+        let footer = &device.data()[CHUNK_HEADER_SIZE + 0x0102..CHUNK_OVERHEAD + 0x0102];
+        assert_eq!(footer, &[0x02, 0x01, 0, 0, 0, 0, 0, 0, 1]);
+
+        let loaded = Chunk::load_from_device(&device, 0).unwrap();
+        assert_eq!(loaded.data_size(), 0x0102);
+        assert!(loaded.allocated() && loaded.is_consistent(&device).unwrap());
+    }
+
+    #[test]
+    fn writes_past_the_device_fail() {
+        let device = MemoryDevice::new(4096, 4096);
+        assert!(Chunk::create(&device, 0, 4096 + 1, true).is_err());
+        assert!(Chunk::load_from_device(&device, 4096 - CHUNK_HEADER_SIZE as u64 + 1).is_err());
+    }
+
+    #[test]
+    fn neighbours_on_one_free_list_coalesce() {
+        let device = MemoryDevice::new(4096, 4096);
+        let head = Chunk::create(&device, 0, CHUNK_OVERHEAD, true).unwrap();
+        let second_offset = CHUNK_OVERHEAD as u64 + 1024;
+        let mut first = Chunk::create(&device, CHUNK_OVERHEAD as u64, 1024, true).unwrap();
+        let mut second = Chunk::create(&device, second_offset, 1024, true).unwrap();
+
+        // The list reads head, first, second, so unlinking first rewrites second
+        second.insert_into_freelist(head.offset, &device).unwrap();
+        first.insert_into_freelist(head.offset, &device).unwrap();
+        let second = Chunk::load_from_device(&device, second_offset).unwrap();
+
+        let merged = Chunk::coalesce_two(first, second, &device).unwrap();
+        assert_eq!(merged.data_size(), 2048 - CHUNK_OVERHEAD);
+        assert!(merged.allocated() && merged.is_consistent(&device).unwrap());
+
+        let head = Chunk::load_from_device(&device, head.offset).unwrap();
+        assert_eq!(head.header.next_free, 0);
+    }
+}
@@ -0,0 +1,63 @@
+use std::ops::Range;
+use std::sync::RwLock;
+
+use super::{DeviceError, Result, StorageDevice};
+
+// A device backed by a Vec, handy for tests and for staging a heap before writing it out
+pub struct MemoryDevice {
+    data: RwLock<Vec<u8>>,
+    size: usize,
+    block_size: usize
+}
+
+impl MemoryDevice {
+    pub fn new(size: usize, block_size: usize) -> MemoryDevice {
+        MemoryDevice {
+            data: RwLock::new(vec![0u8; size]),
+            size: size,
+            block_size: block_size
+        }
+    }
+
+    // A copy of the whole device
+    pub fn data(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+
+    fn _range(&self, offset: u64, size: usize) -> Result<Range<usize>> {
+        let start = offset as usize;
+
+        match start.checked_add(size) {
+            Some(end) if end <= self.size => Ok(start..end),
+            _ => Err(DeviceError::InvalidOffset)
+        }
+    }
+}
+
+impl StorageDevice for MemoryDevice {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize> {
+        let range = self._range(offset, buffer.len())?;
+        buffer.copy_from_slice(&self.data.read().unwrap()[range]);
+
+        Ok(buffer.len())
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize> {
+        let range = self._range(offset, buffer.len())?;
+        self.data.write().unwrap()[range].copy_from_slice(buffer);
+
+        Ok(buffer.len())
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn access_range(&self) -> Range<u64> {
+        0..self.size as u64
+    }
+}
@@ -1,6 +1,10 @@
+use std::io;
 use std::result;
 use std::ops::Range;
 
+pub mod memory;
+
+#[derive(Debug)]
 pub enum DeviceError {
     NotFound,
     PermissionDenied,
@@ -11,9 +15,22 @@ pub enum DeviceError {
 
 pub type Result<T> = result::Result<T, DeviceError>;
 
-pub trait StorageDevice {
+impl From<io::Error> for DeviceError {
+    fn from(error: io::Error) -> DeviceError {
+        match error.kind() {
+            io::ErrorKind::NotFound => DeviceError::NotFound,
+            io::ErrorKind::PermissionDenied => DeviceError::PermissionDenied,
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::WriteZero => DeviceError::NotEnoughSpace,
+            _ => DeviceError::Other(error.raw_os_error().unwrap_or(0))
+        }
+    }
+}
+
+// Writes go through a shared reference, so the arenas of a heap can work on one device from
+// several threads. Implementations have to cope with concurrent accesses of disjoint ranges.
+pub trait StorageDevice: Sync {
     fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize>;
-    fn write_at(&mut self, offset: u64, buffer: &[u8]) -> Result<usize>;
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize>;
     fn block_size(&self) -> usize;
     fn size(&self) -> usize;
     fn access_range(&self) -> Range<u64>;
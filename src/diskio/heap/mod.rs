@@ -1,110 +1,445 @@
+use std::cmp;
 use std::result;
-use super::chunk::Chunk;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+mod superblock;
+
+use super::Arena;
 use super::device::{StorageDevice, DeviceError};
-use super::device;
+use self::superblock::{Superblock, SUPERBLOCK_SIZE};
 
+pub static DEFAULT_ARENA_COUNT: usize = 4;
+
+// The device starts with the superblock, the rest is split evenly into arenas and allocations go
+// round robin over them. Every arena has its own lock, so threads sharing the heap only wait for
+// each other when they happen to work on the same arena.
 pub struct Heap<'a> {
-    device: &'a mut StorageDevice,
-    top_chunk: Chunk,
+    device: &'a StorageDevice,
+    superblock: Superblock,
+    regions: Vec<Range<u64>>,
+    arenas: Vec<Mutex<Arena>>,
+    next_arena: AtomicUsize
 }
 
+#[derive(Debug)]
 pub enum HeapError {
     DeviceError(DeviceError),
-    NotEnoughSpace
+    NotEnoughSpace,
+    InvalidChunk(u64),
+    InvalidSuperblock(&'static str),
+    UnsupportedVersion(u16),
+    // Written by an older version in the byte order of its machine, has to be reformatted
+    NativeEndianFormat
 }
 
 pub type HeapResult<T> = result::Result<T, HeapError>;
-//
-//trait ToHeapResult<T> {
-//    fn to_heap_result(self) -> HeapResult<T>;
-//}
-//
-//impl ToHeapResult<Chunk> for ChunkResult {
-//    fn to_heap_result(self) -> HeapResult<Chunk> {
-//        self.or_else(|error| {
-//            match error {
-//                ChunkOperationError::DeviceError(inner) => {
-//                    Err(HeapError::DeviceError(inner))
-//                },
-//                _ => {
-//                    Err(HeapError::NotEnoughSpace)
-//                }
-//            }
-//        })
-//    }
-//}
+
+impl From<DeviceError> for HeapError {
+    fn from(error: DeviceError) -> HeapError {
+        HeapError::DeviceError(error)
+    }
+}
 
 impl<'a> Heap<'a> {
-//    fn _next_free_chunk(&self, chunk: &Chunk) -> HeapResult<Chunk> {
-//        chunk.next_free(self.device)
-//            .or_else(|error| {
-//                match error {
-//                    ChunkOperationError::DeviceError(inner) => {
-//                        Err(HeapError::DeviceError(inner))
-//                    },
-//                    _ => {
-//                        Err(HeapError::NotEnoughSpace)
-//                    }
-//                }
-//            })
-//    }
-
-//    fn _next_free_chunk_in_order(&self, chunk: &Chunk) -> HeapResult
-
-    pub fn allocate(&mut self, size: usize) -> device::Result<u64> {
-//        let chunk_found = self.top_chunk.as_freelist(self.device).find(|result| {
-//            match result {
-//                Ok(chunk) => chunk.data_size() >= size,
-//                Err(_) => true
-//            }
-//        });
-//
-//        let chunk = match chunk_found {
-//            Some(Ok(chunk)) => chunk,
-//            Some(error) => return error,
-//            None => return Err(device::DeviceError::NotEnoughSpace)
-//        };
-
-        Ok(1)
-
-
-
-        //        let chunk_found = self.top_chunk.iter_freelist(self.device).find(|result| {
-//            match result {
-//                Ok(chunk) => chunk.data_size() >= size,
-//                Err(_) => true
-//            }
-//        });
-//
-//
-//        let prev = chunk.iter_neighbours(self.device).rev().nth(0);
-//        let next = chunk.iter_neighbours(self.device).nth(0);
-//
-//        let chunk = chunk_on_device?;
-//
-//
-//        for result in self.top_chunk.iter_freelist(self.device) {
-//            match result {
-//                Ok(freelist)
-//            }
-//        }
-//        let mut current = self.top_chunk.clone();
-//
-//        while current.data_size() < size {
-//            current = current.next_free(self.device).to_heap_result()?;
-//        }
-//
-//        if current.data_size() - size == 0 {
-//            return Ok(current.offset());
-//        }
-//
-//        let prev = current.prev_free_in_order(self.device);
-//        let next = current.next_free_in_order(self.device);
-//
-//        match (prev, next)
-
-
-        //current.prev_free_in_order()
+    // Initialises a blank device, everything on it is lost
+    pub fn format(device: &'a StorageDevice) -> HeapResult<Heap<'a>> {
+        Self::format_with_arenas(device, DEFAULT_ARENA_COUNT)
+    }
+
+    pub fn format_with_arenas(device: &'a StorageDevice,
+                              arena_count: usize) -> HeapResult<Heap<'a>> {
+
+        let range = device.access_range();
+
+        if range.start != 0 {
+            return Err(HeapError::DeviceError(DeviceError::InvalidOffset));
+        }
+
+        // Arenas start on a block boundary after the superblock
+        let block_size = cmp::max(device.block_size(), 1) as u64;
+        let heap_start = (SUPERBLOCK_SIZE as u64 + block_size - 1) / block_size * block_size;
+
+        if heap_start >= range.end {
+            return Err(HeapError::NotEnoughSpace);
+        }
+
+        let region = heap_start..range.end;
+
+        let arenas = Self::_arena_regions(&region, arena_count, block_size)?.into_iter()
+            .map(|arena_region| Arena::create(device, arena_region))
+            .collect::<HeapResult<Vec<_>>>()?;
+
+        let superblock = Superblock {
+            uuid: Superblock::generate_uuid(),
+            device_size: device.size() as u64,
+            block_size: block_size,
+            heap_region: region,
+            bin_heads: arenas.iter().map(|arena| arena.bin_offsets()).collect()
+        };
+
+        // Written last, so a device that wasn't formatted completely doesn't open
+        superblock.dump(device)?;
+
+        Ok(Self::_new(device, superblock, arenas))
+    }
+
+    // Validates the superblock and mounts the heap described by it
+    pub fn open(device: &'a StorageDevice) -> HeapResult<Heap<'a>> {
+        let superblock = Superblock::load(device)?;
+
+        if superblock.device_size > device.size() as u64 ||
+            superblock.heap_region.end > device.access_range().end {
+
+            return Err(HeapError::InvalidSuperblock("device is smaller than the heap"));
+        }
+
+        // Chunks are aligned to the block size the heap was formatted with
+        if superblock.block_size != cmp::max(device.block_size(), 1) as u64 {
+            return Err(HeapError::InvalidSuperblock("device block size doesn't match the heap"));
+        }
+
+        let arenas = Self::_arena_regions(&superblock.heap_region, superblock.arena_count(),
+                                          superblock.block_size)?
+            .into_iter()
+            .map(|arena_region| Arena::load(device, arena_region))
+            .collect::<HeapResult<Vec<_>>>()?;
+
+        let consistent = arenas.iter().zip(superblock.bin_heads.iter())
+            .all(|(arena, heads)| arena.bin_offsets() == *heads);
+
+        if !consistent {
+            return Err(HeapError::InvalidSuperblock("bin table doesn't match the arenas"));
+        }
+
+        Ok(Self::_new(device, superblock, arenas))
+    }
+
+    fn _new(device: &'a StorageDevice, superblock: Superblock, arenas: Vec<Arena>) -> Heap<'a> {
+        Heap {
+            device: device,
+            superblock: superblock,
+            regions: arenas.iter().map(|arena| arena.region()).collect(),
+            arenas: arenas.into_iter().map(Mutex::new).collect(),
+            next_arena: AtomicUsize::new(0)
+        }
+    }
+
+    // Arenas span the same number of whole blocks, so none of them wastes space on alignment. The
+    // blocks left over at the end of the region aren't used.
+    fn _arena_regions(region: &Range<u64>, arena_count: usize,
+                      block_size: u64) -> HeapResult<Vec<Range<u64>>> {
+
+        if arena_count == 0 || region.end < region.start {
+            return Err(HeapError::NotEnoughSpace);
+        }
+
+        let arena_size = (region.end - region.start) / arena_count as u64 / block_size * block_size;
+
+        if arena_size == 0 {
+            return Err(HeapError::NotEnoughSpace);
+        }
+
+        Ok((0..arena_count as u64).map(|index| {
+            let start = region.start + index * arena_size;
+            start..start + arena_size
+        }).collect())
+    }
+
+    pub fn uuid(&self) -> &[u8; 16] {
+        &self.superblock.uuid
+    }
+
+    pub fn region(&self) -> Range<u64> {
+        self.superblock.heap_region.clone()
+    }
+
+    pub fn arenas(&self) -> &[Mutex<Arena>] {
+        &self.arenas
+    }
+
+    // Returns the device offset of size usable bytes, from the next arena that has room. Every
+    // call starts at a different arena, so concurrent callers spread over the locks.
+    pub fn allocate(&self, size: usize) -> HeapResult<u64> {
+        let first = self.next_arena.fetch_add(1, Ordering::Relaxed);
+
+        for index in (0..self.arenas.len()).map(|i| (first + i) % self.arenas.len()) {
+            match self.arenas[index].lock().unwrap().allocate(self.device, size) {
+                Err(HeapError::NotEnoughSpace) => continue,
+                result => return result
+            }
+        }
+
+        Err(HeapError::NotEnoughSpace)
+    }
+
+    // Takes an offset returned by allocate and gives it back to its arena
+    pub fn free(&self, offset: u64) -> HeapResult<()> {
+        match self.regions.iter().position(|region| region.contains(&offset)) {
+            Some(index) => self.arenas[index].lock().unwrap().free(self.device, offset),
+            None => Err(HeapError::InvalidChunk(offset))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rand;
+    use rand::Rng;
+
+    use super::{Heap, HeapError, DEFAULT_ARENA_COUNT};
+    use super::super::chunk::CHUNK_OVERHEAD;
+    use super::super::device::StorageDevice;
+    use super::super::device::memory::MemoryDevice;
+
+    static DEVICE_SIZE: usize = 16 << 20;
+
+    #[test]
+    fn allocations_are_disjoint_and_freeing_coalesces_everything() {
+        let device = MemoryDevice::new(DEVICE_SIZE, 4096);
+        let heap = Heap::format_with_arenas(&device, 1).unwrap();
+        let capacity = heap.arenas()[0].lock().unwrap().capacity();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..10 {
+            let mut allocations = Vec::new();
+
+            loop {
+                let size = rng.gen_range(0, 512 << 10);
+
+                match heap.allocate(size) {
+                    Ok(offset) => allocations.push((offset, size)),
+                    Err(HeapError::NotEnoughSpace) => break,
+                    Err(error) => panic!("{:?}", error)
+                }
+            }
+
+            let mut sorted = allocations.clone();
+            sorted.sort();
+
+            for pair in sorted.windows(2) {
+                assert!(pair[0].0 + pair[0].1 as u64 <= pair[1].0);
+            }
+
+            if let Some(&(offset, size)) = sorted.last() {
+                assert!(offset + size as u64 <= heap.region().end);
+            }
+
+            rng.shuffle(&mut allocations);
+
+            for &(offset, _) in allocations.iter() {
+                heap.free(offset).unwrap();
+            }
+
+            let offset = heap.allocate(capacity).unwrap();
+            heap.free(offset).unwrap();
+        }
+    }
+
+    #[test]
+    fn formatted_heap_can_be_opened() {
+        let device = MemoryDevice::new(DEVICE_SIZE, 4096);
+
+        let (uuid, offset) = {
+            let heap = Heap::format(&device).unwrap();
+            assert!(heap.region().start >= 4096);
+            (*heap.uuid(), heap.allocate(1000).unwrap())
+        };
+
+        let heap = Heap::open(&device).unwrap();
+        let capacity = heap.arenas()[0].lock().unwrap().capacity();
+
+        assert_eq!(*heap.uuid(), uuid);
+        assert_eq!(heap.arenas().len(), DEFAULT_ARENA_COUNT);
+
+        // The first arena holds the earlier allocation, the others are empty
+        for _ in 1..DEFAULT_ARENA_COUNT {
+            heap.allocate(capacity).unwrap();
+        }
+
+        assert!(heap.allocate(capacity).is_err());
+
+        heap.free(offset).unwrap();
+        heap.allocate(capacity).unwrap();
+    }
+
+    #[test]
+    fn invalid_superblocks_are_rejected() {
+        let device = MemoryDevice::new(DEVICE_SIZE, 4096);
+
+        match Heap::open(&device) {
+            Err(HeapError::InvalidSuperblock(_)) => (),
+            _ => panic!("blank device opened")
+        }
+
+        Heap::format(&device).unwrap();
+
+        let mut bytes = device.data()[0..4096].to_vec();
+        assert_eq!(&bytes[4..6], &[3, 0]);
+
+        for &version in [2, 4].iter() {
+            bytes[4] = version;
+            device.write_at(0, &bytes).unwrap();
+
+            match Heap::open(&device) {
+                Err(HeapError::UnsupportedVersion(found)) => assert_eq!(found, version as u16),
+                _ => panic!("unknown version opened")
+            }
+        }
+
+        for version in [[1, 0], [0, 1]].iter() {
+            bytes[4..6].copy_from_slice(version);
+            device.write_at(0, &bytes).unwrap();
+
+            match Heap::open(&device) {
+                Err(HeapError::NativeEndianFormat) => (),
+                _ => panic!("native-endian superblock opened")
+            }
+        }
+
+        bytes[4..6].copy_from_slice(&[3, 0]);
+        bytes[60] = bytes[60].wrapping_add(1);
+        device.write_at(0, &bytes).unwrap();
+
+        match Heap::open(&device) {
+            Err(HeapError::InvalidSuperblock(_)) => (),
+            _ => panic!("inconsistent bin table opened")
+        }
+
+        bytes[60] = bytes[60].wrapping_sub(1);
+        device.write_at(0, &bytes).unwrap();
+
+        let other_block_size = MemoryDevice::new(DEVICE_SIZE, 512);
+        other_block_size.write_at(0, &device.data()).unwrap();
+
+        match Heap::open(&other_block_size) {
+            Err(HeapError::InvalidSuperblock(_)) => (),
+            _ => panic!("heap opened with a different block size")
+        }
+
+        let small = MemoryDevice::new(DEVICE_SIZE / 2, 4096);
+        small.write_at(0, &device.data()[0..DEVICE_SIZE / 2]).unwrap();
+
+        assert!(Heap::open(&small).is_err());
+        assert!(Heap::open(&device).is_ok());
+    }
+
+    #[test]
+    fn invalid_frees_are_rejected() {
+        let device = MemoryDevice::new(DEVICE_SIZE, 4096);
+        let heap = Heap::format_with_arenas(&device, 1).unwrap();
+
+        let first = heap.allocate(1000).unwrap();
+        let second = heap.allocate(1000).unwrap();
+
+        for &offset in [0, 1, first + 1, second - 1, DEVICE_SIZE as u64 - 1].iter() {
+            assert!(heap.free(offset).is_err());
+        }
+
+        heap.free(first).unwrap();
+        assert!(heap.free(first).is_err());
+        heap.free(second).unwrap();
+    }
+
+    #[test]
+    fn oversized_allocations_fail() {
+        let device = MemoryDevice::new(DEVICE_SIZE, 4096);
+        let heap = Heap::format_with_arenas(&device, 1).unwrap();
+        let capacity = heap.arenas()[0].lock().unwrap().capacity();
+
+        match heap.allocate(capacity + 1) {
+            Err(HeapError::NotEnoughSpace) => (),
+            _ => panic!("allocation larger than the heap succeeded")
+        }
+
+        let small = MemoryDevice::new(4096 + CHUNK_OVERHEAD, 4096);
+        assert!(Heap::format(&small).is_err());
+    }
+
+    #[test]
+    fn allocations_are_aligned_to_the_device_block_size() {
+        for &block_size in [512, 4096].iter() {
+            let device = MemoryDevice::new(DEVICE_SIZE, block_size);
+            let heap = Heap::format(&device).unwrap();
+            let mut offsets = Vec::new();
+
+            for &size in [0, 1, 100, 511, 512, 4096, 5000, 70000, 1 << 20].iter() {
+                let offset = heap.allocate(size).unwrap();
+
+                assert_eq!(offset % block_size as u64, 0, "{} {}", block_size, size);
+                offsets.push(offset);
+            }
+
+            for &offset in offsets.iter() {
+                heap.free(offset).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn threads_share_one_heap() {
+        let device = MemoryDevice::new(DEVICE_SIZE, 4096);
+        let heap = Heap::format(&device).unwrap();
+        let capacity = heap.arenas()[0].lock().unwrap().capacity();
+
+        let mut allocations = thread::scope(|scope| {
+            let workers = (0..8).map(|_| scope.spawn(|| {
+                let mut rng = rand::thread_rng();
+                let mut kept = Vec::new();
+
+                for _ in 0..100 {
+                    let size = rng.gen_range(0, 16 << 10);
+                    let offset = heap.allocate(size).unwrap();
+
+                    match rng.gen() {
+                        true => kept.push((offset, size)),
+                        _    => heap.free(offset).unwrap()
+                    }
+                }
+
+                kept
+            })).collect::<Vec<_>>();
+
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+        });
+
+        allocations.sort();
+
+        for pair in allocations.windows(2) {
+            assert!(pair[0].0 + pair[0].1 as u64 <= pair[1].0);
+        }
+
+        for &(offset, _) in allocations.iter() {
+            heap.free(offset).unwrap();
+        }
+
+        for _ in 0..DEFAULT_ARENA_COUNT {
+            heap.allocate(capacity).unwrap();
+        }
+    }
+
+    #[test]
+    fn allocations_are_spread_over_arenas() {
+        let device = MemoryDevice::new(DEVICE_SIZE, 4096);
+        let heap = Heap::format_with_arenas(&device, 4).unwrap();
+        let capacity = heap.arenas()[0].lock().unwrap().capacity();
+
+        let offsets = (0..4).map(|_| heap.allocate(capacity).unwrap())
+            .collect::<Vec<_>>();
+
+        for (arena, &offset) in heap.arenas().iter().zip(offsets.iter()) {
+            assert!(arena.lock().unwrap().contains(offset));
+        }
+
+        assert!(heap.allocate(0).is_err());
+
+        for &offset in offsets.iter() {
+            heap.free(offset).unwrap();
+        }
 
+        heap.allocate(capacity).unwrap();
     }
 }
@@ -0,0 +1,152 @@
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian};
+use rand;
+use rand::Rng;
+
+use super::{HeapError, HeapResult};
+use super::super::device::StorageDevice;
+
+// The superblock sits at offset 0 of the device, little-endian:
+//
+//   0  magic             4 bytes, "DRHP"
+//   4  format version    u16
+//   6  reserved          u16, zero
+//   8  uuid              16 bytes
+//  24  device size       u64
+//  32  heap start        u64
+//  40  heap end          u64
+//  48  arena count       u32
+//  52  bin count         u32, per arena
+//  56  block size        u32, chunk data is aligned to it
+//  60  bin heads         u64 each, arena by arena
+//
+// and is padded to SUPERBLOCK_SIZE. Bump the version on any change of the layout, including the
+// chunk layout. Version 1 wrote the superblock and chunks in the native byte order, version 2
+// didn't align chunks.
+static SUPERBLOCK_MAGIC: &[u8; 4] = b"DRHP";
+static SUPERBLOCK_VERSION: u16 = 3;
+static NATIVE_ENDIAN_VERSION: u16 = 1;
+static SUPERBLOCK_HEADER_SIZE: usize = 60;
+pub static SUPERBLOCK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Superblock {
+    pub uuid: [u8; 16],
+    pub device_size: u64,
+    pub block_size: u64,
+    pub heap_region: Range<u64>,
+    pub bin_heads: Vec<Vec<u64>>
+}
+
+fn ensure_format(condition: bool, reason: &'static str) -> HeapResult<()> {
+    match condition {
+        true => Ok(()),
+        _    => Err(HeapError::InvalidSuperblock(reason))
+    }
+}
+
+impl Superblock {
+    // A random (version 4) UUID, so devices can be told apart
+    pub fn generate_uuid() -> [u8; 16] {
+        let mut uuid = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut uuid);
+
+        uuid[6] = (uuid[6] & 0x0f) | 0x40;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+        uuid
+    }
+
+    pub fn arena_count(&self) -> usize {
+        self.bin_heads.len()
+    }
+
+    pub fn load(device: &StorageDevice) -> HeapResult<Superblock> {
+        let mut bytes = vec![0u8; SUPERBLOCK_SIZE];
+        device.read_at(0, &mut bytes)?;
+
+        ensure_format(&bytes[0..4] == SUPERBLOCK_MAGIC, "not a heap superblock")?;
+
+        let version = LittleEndian::read_u16(&bytes[4..6]);
+
+        // Version 1 reads as 1 or 256, depending on the architecture that wrote it
+        if version == NATIVE_ENDIAN_VERSION || version.swap_bytes() == NATIVE_ENDIAN_VERSION {
+            return Err(HeapError::NativeEndianFormat);
+        }
+
+        if version != SUPERBLOCK_VERSION {
+            return Err(HeapError::UnsupportedVersion(version));
+        }
+
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&bytes[8..24]);
+
+        let device_size = LittleEndian::read_u64(&bytes[24..32]);
+        let heap_start = LittleEndian::read_u64(&bytes[32..40]);
+        let heap_region = heap_start..LittleEndian::read_u64(&bytes[40..48]);
+        let arena_count = LittleEndian::read_u32(&bytes[48..52]) as usize;
+        let bin_count = LittleEndian::read_u32(&bytes[52..56]) as usize;
+        let block_size = LittleEndian::read_u32(&bytes[56..60]) as u64;
+
+        ensure_format(heap_region.start >= SUPERBLOCK_SIZE as u64 &&
+                      heap_region.start < heap_region.end &&
+                      heap_region.end <= device_size,
+                      "heap region outside of the device")?;
+        ensure_format(block_size > 0, "zero block size")?;
+        ensure_format(arena_count > 0 && bin_count > 0 &&
+                      arena_count.saturating_mul(bin_count).saturating_mul(8) <=
+                          SUPERBLOCK_SIZE - SUPERBLOCK_HEADER_SIZE,
+                      "bin table doesn't fit the superblock")?;
+
+        let bin_heads = bytes[SUPERBLOCK_HEADER_SIZE..]
+            .chunks(8)
+            .take(arena_count * bin_count)
+            .map(LittleEndian::read_u64)
+            .collect::<Vec<_>>()
+            .chunks(bin_count)
+            .map(|heads| heads.to_vec())
+            .collect();
+
+        Ok(Superblock {
+            uuid: uuid,
+            device_size: device_size,
+            block_size: block_size,
+            heap_region: heap_region,
+            bin_heads: bin_heads
+        })
+    }
+
+    pub fn dump(&self, device: &StorageDevice) -> HeapResult<()> {
+        let bin_count = self.bin_heads.first().map_or(0, |heads| heads.len());
+
+        ensure_format(self.bin_heads.iter().all(|heads| heads.len() == bin_count),
+                      "arenas with different bin counts")?;
+        ensure_format(self.arena_count() * bin_count * 8 <= SUPERBLOCK_SIZE - SUPERBLOCK_HEADER_SIZE,
+                      "bin table doesn't fit the superblock")?;
+        ensure_format(self.block_size > 0 && self.block_size <= u32::MAX as u64,
+                      "block size out of range")?;
+
+        let mut bytes = vec![0u8; SUPERBLOCK_SIZE];
+
+        bytes[0..4].copy_from_slice(SUPERBLOCK_MAGIC);
+        LittleEndian::write_u16(&mut bytes[4..6], SUPERBLOCK_VERSION);
+        bytes[8..24].copy_from_slice(&self.uuid);
+        LittleEndian::write_u64(&mut bytes[24..32], self.device_size);
+        LittleEndian::write_u64(&mut bytes[32..40], self.heap_region.start);
+        LittleEndian::write_u64(&mut bytes[40..48], self.heap_region.end);
+        LittleEndian::write_u32(&mut bytes[48..52], self.arena_count() as u32);
+        LittleEndian::write_u32(&mut bytes[52..56], bin_count as u32);
+        LittleEndian::write_u32(&mut bytes[56..60], self.block_size as u32);
+
+        let heads = self.bin_heads.iter().flat_map(|heads| heads.iter());
+
+        for (slot, &offset) in bytes[SUPERBLOCK_HEADER_SIZE..].chunks_mut(8).zip(heads) {
+            LittleEndian::write_u64(slot, offset);
+        }
+
+        device.write_at(0, &bytes)?;
+
+        Ok(())
+    }
+}
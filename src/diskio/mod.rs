@@ -1,32 +1,394 @@
-use std::collections::HashMap;
-use std::io::{Read, Write, Cursor};
-use std::mem::size_of;
-
-
-use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp;
+use std::ops::Range;
 
 mod device;
 mod chunk;
 mod heap;
 
+use self::chunk::{Chunk, CHUNK_HEADER_SIZE, CHUNK_OVERHEAD};
+use self::device::{StorageDevice, DeviceError};
+use self::heap::{HeapError, HeapResult};
 
-struct Bin {
-//    fd: RawFd,
+// Bin 0 holds everything below 64 KiB, bins 1 to 7 one power of two each from 64 KiB to 4 MiB and
+// the last bin everything from 8 MiB up
+static MIN_SIZE_CLASS_SHIFT: usize = 16;
+static MAX_SIZE_CLASS_SHIFT: usize = 22;
+pub static BIN_COUNT: usize = MAX_SIZE_CLASS_SHIFT - MIN_SIZE_CLASS_SHIFT + 3;
+
+// A segregated free list, its head is a zero sized allocated chunk in the bin table of the arena
+pub struct Bin {
     top_chunk_offset: u64
 }
 
 impl Bin {
-    fn allocate(size: usize) -> u64 {
-        unimplemented!()
+    pub fn index(size: usize) -> usize {
+        if size < 1 << MIN_SIZE_CLASS_SHIFT {
+            return 0;
+        }
+
+        let shift = 63 - (size as u64).leading_zeros() as usize;
+        cmp::min(shift - MIN_SIZE_CLASS_SHIFT + 1, BIN_COUNT - 1)
+    }
+
+    // Takes the first chunk of at least size bytes off the bin, looking at no more than limit
+    // chunks
+    fn allocate(&self, device: &StorageDevice, size: usize, limit: usize)
+        -> HeapResult<Option<Chunk>> {
+
+        let found = Chunk::load_from_device(device, self.top_chunk_offset)?
+            .as_freelist(device)
+            .iter()
+            .skip(1)
+            .take(limit)
+            .find(|result| {
+                match *result {
+                    Ok(ref chunk) => chunk.data_size() >= size,
+                    Err(_) => true
+                }
+            });
+
+        match found {
+            Some(result) => {
+                let mut chunk = result?;
+                chunk.remove_from_freelist(device)?;
+                Ok(Some(chunk))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn insert(&self, chunk: &mut Chunk, device: &StorageDevice) -> HeapResult<()> {
+        chunk.insert_into_freelist(self.top_chunk_offset, device)?;
+        Ok(())
     }
 }
 
-struct Arena {
-//    fd: RawFd,
-    size_classes: i64,
-//    bins: HashMap<usize, ChunkHeader>
+// An independent heap over a part of the device. The region starts with the bin table and ends
+// with a zero sized allocated chunk, so coalescing never runs past either end. Chunk data starts
+// on a device block boundary and chunks span whole blocks, so allocations can be accessed with
+// O_DIRECT. An allocated prologue chunk fills the gap between the bin table and the first block
+// boundary, whatever is left at the end of the region stays unused. Arenas share no state on the
+// device, so arenas of one device can be used from different threads. Changing an arena takes
+// &mut self, the heap keeps every arena behind its own lock.
+pub struct Arena {
+    region: Range<u64>,
+    alignment: u64,
+    bins: Vec<Bin>
 }
 
 impl Arena {
+    // Lays out an empty arena over region, everything in it is lost
+    pub fn create(device: &StorageDevice, region: Range<u64>) -> HeapResult<Arena> {
+        Self::_check_region(device, &region)?;
+
+        for index in 0..BIN_COUNT {
+            let offset = region.start + (index * CHUNK_OVERHEAD) as u64;
+            Chunk::create(device, offset, CHUNK_OVERHEAD, true)?;
+        }
+
+        let alignment = Self::_alignment(device);
+        let free_offset = Self::_first_chunk_offset(&region, alignment);
+        let epilogue_offset = Self::_epilogue_offset(&region, alignment);
+
+        if free_offset > region.start + (BIN_COUNT * CHUNK_OVERHEAD) as u64 {
+            let prologue_offset = region.start + (BIN_COUNT * CHUNK_OVERHEAD) as u64;
+            Chunk::create(device, prologue_offset, (free_offset - prologue_offset) as usize, true)?;
+        }
+
+        Chunk::create(device, epilogue_offset, CHUNK_OVERHEAD, true)?;
+
+        let arena = Self::load(device, region)?;
+
+        let mut free = Chunk::create(device, free_offset,
+                                     (epilogue_offset - free_offset) as usize, false)?;
+        arena._insert(&mut free, device)?;
+
+        Ok(arena)
+    }
+
+    // Opens an arena previously laid out by create
+    pub fn load(device: &StorageDevice, region: Range<u64>) -> HeapResult<Arena> {
+        Self::_check_region(device, &region)?;
+        let mut bins = Vec::with_capacity(BIN_COUNT);
+
+        for index in 0..BIN_COUNT {
+            let offset = region.start + (index * CHUNK_OVERHEAD) as u64;
+            let head = Chunk::load_from_device(device, offset)?;
+
+            if !head.allocated() || head.data_size() != 0 {
+                return Err(HeapError::InvalidChunk(offset));
+            }
+
+            bins.push(Bin { top_chunk_offset: offset });
+        }
+
+        let alignment = Self::_alignment(device);
+        let prologue_offset = region.start + (BIN_COUNT * CHUNK_OVERHEAD) as u64;
+        let first_offset = Self::_first_chunk_offset(&region, alignment);
+
+        if first_offset > prologue_offset {
+            let prologue = Chunk::load_from_device(device, prologue_offset)?;
+
+            if !prologue.allocated() || prologue.offset() + prologue.size() as u64 != first_offset {
+                return Err(HeapError::InvalidChunk(prologue_offset));
+            }
+        }
+
+        Ok(Arena {
+            region: region,
+            alignment: alignment,
+            bins: bins
+        })
+    }
+
+    pub fn region(&self) -> Range<u64> {
+        self.region.clone()
+    }
+
+    pub fn bin_offsets(&self) -> Vec<u64> {
+        self.bins.iter().map(|bin| bin.top_chunk_offset).collect()
+    }
+
+    pub fn contains(&self, offset: u64) -> bool {
+        offset >= self.region.start && offset < self.region.end
+    }
+
+    // The largest allocation the arena can serve while it's empty
+    pub fn capacity(&self) -> usize {
+        let first_offset = Self::_first_chunk_offset(&self.region, self.alignment);
+        let epilogue_offset = Self::_epilogue_offset(&self.region, self.alignment);
+
+        (epilogue_offset - first_offset) as usize - CHUNK_OVERHEAD
+    }
+
+    // Returns the device offset of size usable bytes. Every chunk in a bin above the one of size
+    // is large enough, so only the first chunk of each of them is looked at. The smallest and the
+    // largest bin don't have an upper bound and are searched first fit right away. The power of
+    // two bins are only searched once every larger bin came up empty, so the usual allocation
+    // stays O(1) without missing a chunk that fits. The size is rounded up so that the chunk spans
+    // whole device blocks.
+    pub fn allocate(&mut self, device: &StorageDevice, size: usize) -> HeapResult<u64> {
+        let alignment = self.alignment as usize;
+        let size = match size.checked_add(CHUNK_OVERHEAD + alignment - 1) {
+            Some(total) => total / alignment * alignment - CHUNK_OVERHEAD,
+            None => return Err(HeapError::NotEnoughSpace)
+        };
+
+        let first = Bin::index(size);
+        let limit = match first == 0 || first == BIN_COUNT - 1 {
+            true => usize::MAX,
+            _    => 1
+        };
+
+        let mut found = self.bins[first].allocate(device, size, limit)?;
+
+        for bin in self.bins.iter().skip(first + 1) {
+            if found.is_some() {
+                break;
+            }
+
+            found = bin.allocate(device, size, 1)?;
+        }
+
+        if found.is_none() && limit == 1 {
+            found = self.bins[first].allocate(device, size, usize::MAX)?;
+        }
+
+        let mut chunk = match found {
+            Some(chunk) => chunk,
+            None => return Err(HeapError::NotEnoughSpace)
+        };
+
+        if let Some(mut rest) = chunk.split(size, device)? {
+            self._insert(&mut rest, device)?;
+        }
+
+        Ok(chunk.data_offset())
+    }
+
+    // Takes an offset returned by allocate and merges the chunk with its free neighbours
+    pub fn free(&mut self, device: &StorageDevice, offset: u64) -> HeapResult<()> {
+        let chunk = self._allocated_chunk(device, offset)?;
+
+        let mut merged = match chunk.unallocated_neighbours(device)? {
+            (None, None) => chunk,
+            (Some(prev), None) => Chunk::coalesce_two(prev, chunk, device)?,
+            (None, Some(next)) => Chunk::coalesce_two(chunk, next, device)?,
+            (Some(prev), Some(next)) => Chunk::coalesce_three(prev, chunk, next, device)?
+        };
+
+        self._insert(&mut merged, device)
+    }
+
+    // The bin table, the prologue, the epilogue and at least one chunk have to fit
+    fn _check_region(device: &StorageDevice, region: &Range<u64>) -> HeapResult<()> {
+        let range = device.access_range();
 
+        if region.start < range.start || region.end > range.end || region.start >= region.end {
+            return Err(HeapError::DeviceError(DeviceError::InvalidOffset));
+        }
+
+        let alignment = Self::_alignment(device);
+
+        if Self::_epilogue_offset(region, alignment) <
+            Self::_first_chunk_offset(region, alignment) + CHUNK_OVERHEAD as u64 {
+
+            return Err(HeapError::NotEnoughSpace);
+        }
+
+        Ok(())
+    }
+
+    fn _alignment(device: &StorageDevice) -> u64 {
+        cmp::max(device.block_size(), 1) as u64
+    }
+
+    // The first chunk after the bin table whose data starts on a block boundary, leaving room for
+    // the prologue if there is a gap
+    fn _first_chunk_offset(region: &Range<u64>, alignment: u64) -> u64 {
+        let table_end = region.start + (BIN_COUNT * CHUNK_OVERHEAD) as u64;
+        let header_size = CHUNK_HEADER_SIZE as u64;
+        let mut data_offset = (table_end + header_size + alignment - 1) / alignment * alignment;
+
+        while data_offset - header_size > table_end &&
+            data_offset - header_size < table_end + CHUNK_OVERHEAD as u64 {
+
+            data_offset += alignment;
+        }
+
+        data_offset - header_size
+    }
+
+    // The last place for the epilogue that keeps the chunk before it a whole number of blocks
+    fn _epilogue_offset(region: &Range<u64>, alignment: u64) -> u64 {
+        let footer_size = (CHUNK_OVERHEAD - CHUNK_HEADER_SIZE) as u64;
+        let data_offset = region.end.saturating_sub(footer_size) / alignment * alignment;
+
+        data_offset.saturating_sub(CHUNK_HEADER_SIZE as u64)
+    }
+
+    fn _insert(&self, chunk: &mut Chunk, device: &StorageDevice) -> HeapResult<()> {
+        self.bins[Bin::index(chunk.data_size())].insert(chunk, device)
+    }
+
+    // Loads the chunk owning the data at offset, refusing anything that wasn't handed out by
+    // allocate or was already freed
+    fn _allocated_chunk(&self, device: &StorageDevice, offset: u64) -> HeapResult<Chunk> {
+        let first_offset = Self::_first_chunk_offset(&self.region, self.alignment);
+        let epilogue_offset = Self::_epilogue_offset(&self.region, self.alignment);
+
+        if offset < first_offset || offset >= epilogue_offset || offset % self.alignment != 0 {
+            return Err(HeapError::InvalidChunk(offset));
+        }
+
+        let chunk = Chunk::load_from_data_offset(device, offset)?;
+
+        if chunk.offset() < first_offset ||
+            chunk.data_size() as u64 >
+                (epilogue_offset - chunk.offset()).saturating_sub(CHUNK_OVERHEAD as u64) ||
+            !chunk.allocated() ||
+            !chunk.is_consistent(device)? {
+
+            return Err(HeapError::InvalidChunk(offset));
+        }
+
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arena, Bin, BIN_COUNT};
+    use super::device::memory::MemoryDevice;
+
+    static ARENA_SIZE: usize = 64 << 20;
+
+    #[test]
+    fn sizes_map_to_their_power_of_two_bin() {
+        assert_eq!(Bin::index(0), 0);
+        assert_eq!(Bin::index((64 << 10) - 1), 0);
+        assert_eq!(Bin::index(64 << 10), 1);
+        assert_eq!(Bin::index((128 << 10) - 1), 1);
+        assert_eq!(Bin::index(1 << 20), 5);
+        assert_eq!(Bin::index(4 << 20), BIN_COUNT - 2);
+        assert_eq!(Bin::index((8 << 20) - 1), BIN_COUNT - 2);
+        assert_eq!(Bin::index(8 << 20), BIN_COUNT - 1);
+        assert_eq!(Bin::index(1 << 40), BIN_COUNT - 1);
+    }
+
+    #[test]
+    fn freed_blocks_are_reused_from_their_bin() {
+        let device = MemoryDevice::new(ARENA_SIZE, 4096);
+        let mut arena = Arena::create(&device, 0..ARENA_SIZE as u64).unwrap();
+
+        for &size in [64 << 10, 256 << 10, 1 << 20, 4 << 20].iter() {
+            let blocks = (0..6).map(|_| arena.allocate(&device, size).unwrap())
+                .collect::<Vec<_>>();
+
+            // Every other block, so none of them coalesce
+            for &offset in blocks.iter().step_by(2) {
+                arena.free(&device, offset).unwrap();
+            }
+
+            for _ in 0..3 {
+                let offset = arena.allocate(&device, size).unwrap();
+                assert!(blocks.iter().step_by(2).any(|&block| block == offset));
+            }
+
+            for &offset in blocks.iter() {
+                arena.free(&device, offset).unwrap();
+            }
+        }
+
+        let offset = arena.allocate(&device, arena.capacity()).unwrap();
+        arena.free(&device, offset).unwrap();
+    }
+
+    #[test]
+    fn larger_chunks_behind_the_bin_head_are_found() {
+        let size = 256 << 10;
+        let device = MemoryDevice::new(size, 4096);
+        let mut arena = Arena::create(&device, 0..size as u64).unwrap();
+
+        // Leaves a 120000 and a 70000 bytes chunk in the same bin, the smaller one at its head
+        let large = arena.allocate(&device, 120000).unwrap();
+        let separator = arena.allocate(&device, 0).unwrap();
+        let small = arena.allocate(&device, 70000).unwrap();
+        let rest = arena.allocate(&device, 0).unwrap();
+
+        while arena.allocate(&device, 0).is_ok() {}
+
+        arena.free(&device, large).unwrap();
+        arena.free(&device, small).unwrap();
+
+        let offset = arena.allocate(&device, 100000).unwrap();
+        assert_eq!(offset, large);
+
+        for &offset in [offset, separator, rest].iter() {
+            arena.free(&device, offset).unwrap();
+        }
+    }
+
+    #[test]
+    fn arenas_on_one_device_are_independent() {
+        let device = MemoryDevice::new(ARENA_SIZE, 4096);
+        let half = ARENA_SIZE as u64 / 2;
+
+        let mut first = Arena::create(&device, 0..half).unwrap();
+        let mut second = Arena::create(&device, half..ARENA_SIZE as u64).unwrap();
+
+        let a = first.allocate(&device, 1 << 20).unwrap();
+        let b = second.allocate(&device, 1 << 20).unwrap();
+
+        assert!(first.contains(a) && second.contains(b));
+        assert!(second.free(&device, a).is_err());
+
+        first.free(&device, a).unwrap();
+        second.free(&device, b).unwrap();
+
+        let mut reloaded = Arena::load(&device, half..ARENA_SIZE as u64).unwrap();
+        let capacity = reloaded.capacity();
+        reloaded.allocate(&device, capacity).unwrap();
+        assert!(first.allocate(&device, capacity + 1).is_err());
+    }
 }
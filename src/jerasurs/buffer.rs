@@ -1,83 +1,323 @@
 use std::vec::Vec;
 use std::option::Option;
 use std::slice;
-use std::mem;
+use std::ops::{Deref, DerefMut, Range};
+use std::ptr::NonNull;
+use std::alloc;
+use std::alloc::Layout;
 
-use libc::{free, c_void};
+use byteorder::{ByteOrder, LittleEndian};
+use crc32c;
+#[cfg(feature = "jerasure")]
+use std::marker::PhantomData;
+
+#[cfg(feature = "jerasure")]
 use super::native::RawBlockBuffer;
+use super::{CodecError, Result, Technique, ensure_geometry};
+use super::layout::StripeLayout;
+
+// Page alignment, so the buffer can be handed to O_DIRECT reads and writes as is
+static BUFFER_ALIGNMENT: usize = 4096;
+
+// A single zero initialized heap allocation aligned to BUFFER_ALIGNMENT
+struct AlignedMemory {
+    _data: NonNull<u8>,
+    _layout: Layout
+}
+
+// The allocation is uniquely owned, and shared references only allow reads through Deref
+unsafe impl Send for AlignedMemory {}
+unsafe impl Sync for AlignedMemory {}
+
+impl AlignedMemory {
+    fn zeroed(size: usize) -> Result<AlignedMemory> {
+        ensure_geometry(size > 0, "buffer size must be positive")?;
+
+        let layout = Layout::from_size_align(size, BUFFER_ALIGNMENT)
+            .map_err(|_| CodecError::InvalidGeometry("buffer is too large"))?;
+
+        let data = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+            Some(data) => data,
+            None => alloc::handle_alloc_error(layout)
+        };
+
+        Ok(AlignedMemory { _data: data, _layout: layout })
+    }
+}
+
+impl Clone for AlignedMemory {
+    fn clone(&self) -> AlignedMemory {
+        let mut result = AlignedMemory::zeroed(self._layout.size())
+            .expect("layout of an existing allocation");
+
+        result.copy_from_slice(self);
+        result
+    }
+}
 
+impl Deref for AlignedMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self._data.as_ptr(), self._layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedMemory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self._data.as_ptr(), self._layout.size()) }
+    }
+}
+
+impl Drop for AlignedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            alloc::dealloc(self._data.as_ptr(), self._layout);
+        }
+    }
+}
+
+// Serialized blocks start with a fixed size little-endian header:
+//
+//   0  magic             4 bytes, "DRBK"
+//   4  format version    u16
+//   6  codec id          u8, Technique::id()
+//   7  w                 u8
+//   8  k                 u32
+//  12  m                 u32
+//  16  packet size       u32
+//  20  block id          u32
+//  24  stripe id         u64
+//  32  data size         u64, of the whole stripe before padding
+//  40  checksum          u32, CRC32C of the block data
+//  44  block size        u32
+//  48  local groups      u32, LRC only, zero for the other codecs
+//
+// followed by the block data. Bump the version on any change of the layout.
+static BLOCK_MAGIC: &[u8; 4] = b"DRBK";
+static BLOCK_FORMAT_VERSION: u16 = 2;
+pub static BLOCK_HEADER_SIZE: usize = 52;
+
+// Upper bounds of the geometries a header may describe, so a forged one can't make us compute
+// overflowing sizes or allocate huge buffers
+pub static MAX_BLOCK_COUNT: usize = 1024;
+static MAX_WORD_SIZE: usize = 32;
+static MAX_PACKET_SIZE: usize = 1 << 20;
+
+fn ensure_format(condition: bool, reason: &'static str) -> Result<()> {
+    match condition {
+        true => Ok(()),
+        _    => Err(CodecError::InvalidBlockFormat(reason))
+    }
+}
+
+// Everything needed to rebuild a stripe from its blocks, without any external metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StripeInfo {
+    pub technique: Technique,
+    pub data_block_count: usize,
+    pub parity_block_count: usize,
+    pub word_size: usize,
+    pub packet_size: usize,
+    pub stripe_id: u64,
+    pub data_size: usize,
+    pub local_group_count: usize
+}
+
+impl StripeInfo {
+    pub fn layout(&self) -> Result<StripeLayout> {
+        ensure_format(self.data_block_count > 0 &&
+                      self.parity_block_count <= MAX_BLOCK_COUNT &&
+                      self.data_block_count <= MAX_BLOCK_COUNT - self.parity_block_count,
+                      "block count out of range")?;
+        ensure_format(self.word_size > 0 && self.word_size <= MAX_WORD_SIZE,
+                      "word size out of range")?;
+        ensure_format(self.packet_size > 0 && self.packet_size <= MAX_PACKET_SIZE,
+                      "packet size out of range")?;
+        ensure_format(match self.technique {
+                          Technique::Lrc => self.local_group_count > 0 &&
+                              self.local_group_count <= self.data_block_count &&
+                              self.local_group_count <= self.parity_block_count,
+                          _ => self.local_group_count == 0
+                      }, "local group count out of range")?;
+
+        let chunk_size = super::checked_chunk_size(
+            self.data_block_count, self.word_size, self.packet_size
+        ).ok_or(CodecError::InvalidBlockFormat("chunk size out of range"))?;
+
+        StripeLayout::new(self.data_size, self.data_block_count, chunk_size)
+    }
+}
+
+// A standalone copy of a single block, that's what travels between nodes. The checksum is the one
+// computed at encoding time, blocks without one are trusted as is.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Block {
     _id: usize,
-    _data: *mut u8,
-    _size: usize,
-    _owned: bool,
+    _data: Vec<u8>,
+    _checksum: Option<u32>,
+    _stripe_info: Option<StripeInfo>
 }
 
 impl Block {
     pub fn new(id: usize, data: &[u8]) -> Block {
-        let mut data_vec = data.to_vec();
-        data_vec.shrink_to_fit();
+        Block::from_vec(id, data.to_vec())
+    }
 
-        let result = Block {
+    pub fn from_vec(id: usize, data: Vec<u8>) -> Block {
+        Block {
             _id: id,
-            _data: data_vec.as_mut_ptr(),
-            _size: data_vec.len(),
-            _owned: true
-        };
-
-        mem::forget(data_vec);
-
-        result
+            _data: data,
+            _checksum: None,
+            _stripe_info: None
+        }
     }
 
-    fn for_buffer_view(id: usize, data: *mut u8, size: usize) -> Block {
+    pub fn with_checksum(id: usize, data: Vec<u8>, checksum: u32) -> Block {
         Block {
             _id: id,
             _data: data,
-            _size: size,
-            _owned: false
+            _checksum: Some(checksum),
+            _stripe_info: None
         }
     }
 
+    // Only blocks taken from an encoded BlockBuffer know their stripe, the rest can't be serialized
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let info = self._stripe_info.ok_or(
+            CodecError::InvalidBlockFormat("block doesn't know its stripe")
+        )?;
+
+        // Anything deserialize() would refuse isn't written in the first place
+        info.layout()?;
+
+        ensure_format(self._data.len() <= u32::MAX as usize, "block doesn't fit into the header")?;
+
+        let mut result = vec![0u8; BLOCK_HEADER_SIZE];
+
+        result[0..4].copy_from_slice(BLOCK_MAGIC);
+        LittleEndian::write_u16(&mut result[4..6], BLOCK_FORMAT_VERSION);
+        result[6] = info.technique.id();
+        result[7] = info.word_size as u8;
+        LittleEndian::write_u32(&mut result[8..12], info.data_block_count as u32);
+        LittleEndian::write_u32(&mut result[12..16], info.parity_block_count as u32);
+        LittleEndian::write_u32(&mut result[16..20], info.packet_size as u32);
+        LittleEndian::write_u32(&mut result[20..24], self._id as u32);
+        LittleEndian::write_u64(&mut result[24..32], info.stripe_id);
+        LittleEndian::write_u64(&mut result[32..40], info.data_size as u64);
+        LittleEndian::write_u32(&mut result[40..44], self._checksum.unwrap_or_else(|| {
+            crc32c::crc32c(&self._data)
+        }));
+        LittleEndian::write_u32(&mut result[44..48], self._data.len() as u32);
+        LittleEndian::write_u32(&mut result[48..52], info.local_group_count as u32);
+
+        result.extend_from_slice(&self._data);
+        Ok(result)
+    }
+
+    // The checksum isn't verified here, decoding rejects the block if it doesn't match
+    pub fn deserialize(bytes: &[u8]) -> Result<Block> {
+        ensure_format(bytes.len() >= BLOCK_HEADER_SIZE, "truncated header")?;
+        ensure_format(&bytes[0..4] == BLOCK_MAGIC, "bad magic")?;
+        ensure_format(LittleEndian::read_u16(&bytes[4..6]) == BLOCK_FORMAT_VERSION,
+                      "unsupported format version")?;
+
+        let technique = Technique::from_id(bytes[6]).ok_or(
+            CodecError::InvalidBlockFormat("unknown codec")
+        )?;
+
+        let info = StripeInfo {
+            technique: technique,
+            data_block_count: LittleEndian::read_u32(&bytes[8..12]) as usize,
+            parity_block_count: LittleEndian::read_u32(&bytes[12..16]) as usize,
+            word_size: bytes[7] as usize,
+            packet_size: LittleEndian::read_u32(&bytes[16..20]) as usize,
+            stripe_id: LittleEndian::read_u64(&bytes[24..32]),
+            data_size: LittleEndian::read_u64(&bytes[32..40]) as usize,
+            local_group_count: LittleEndian::read_u32(&bytes[48..52]) as usize
+        };
+
+        let id = LittleEndian::read_u32(&bytes[20..24]) as usize;
+        let block_size = LittleEndian::read_u32(&bytes[44..48]) as usize;
+
+        let layout = info.layout()?;
+
+        ensure_format(id < info.data_block_count + info.parity_block_count, "block id out of range")?;
+        ensure_format(bytes.len() - BLOCK_HEADER_SIZE == block_size, "truncated block")?;
+        ensure_format(block_size == layout.block_size(), "block size doesn't match the stripe")?;
+
+        Ok(Block {
+            _id: id,
+            _data: bytes[BLOCK_HEADER_SIZE..].to_vec(),
+            _checksum: Some(LittleEndian::read_u32(&bytes[40..44])),
+            _stripe_info: Some(info)
+        })
+    }
+
     pub fn id(&self) -> usize {
         self._id
     }
 
-    pub fn clone_from_slice(&mut self, data: &[u8]) {
-        assert!(data.len() <= self._size);
-        self.data_mut().clone_from_slice(data);
+    pub fn checksum(&self) -> Option<u32> {
+        self._checksum
+    }
+
+    pub fn stripe_info(&self) -> Option<&StripeInfo> {
+        self._stripe_info.as_ref()
+    }
+
+    pub fn is_intact(&self) -> bool {
+        self._checksum.map_or(true, |checksum| crc32c::crc32c(&self._data) == checksum)
+    }
+
+    pub fn clone_from_slice(&mut self, data: &[u8]) -> Result<()> {
+        ensure_geometry(data.len() == self._data.len(), "slice doesn't match the block size")?;
+        self._data.clone_from_slice(data);
+
+        Ok(())
     }
 
     pub fn data(&self) -> &[u8] {
-        unsafe {
-            slice::from_raw_parts(self._data, self._size)
-        }
+        &self._data[..]
     }
 
     pub fn data_mut(&mut self) -> &mut [u8] {
-        unsafe {
-            slice::from_raw_parts_mut(self._data, self._size)
-        }
+        &mut self._data[..]
     }
 }
 
-impl Drop for Block {
-    fn drop(&mut self) {
-        if self._owned {
-            unsafe {
-                free(self._data as *mut c_void);
-            }
-        }
+// Per block pointers into a BlockBuffer, in the form Jerasure expects them. The mutable borrow
+// keeps the buffer alive and untouched while the pointers are in use.
+#[cfg(feature = "jerasure")]
+pub struct RawBlockPointers<'a> {
+    _pointers: Vec<*mut u8>,
+    _data_block_count: usize,
+    _buffer: PhantomData<&'a mut [u8]>
+}
+
+#[cfg(feature = "jerasure")]
+impl<'a> RawBlockPointers<'a> {
+    pub fn data(&mut self) -> RawBlockBuffer {
+        self._pointers.as_mut_ptr()
+    }
+
+    pub fn parity(&mut self) -> RawBlockBuffer {
+        self._pointers[self._data_block_count..].as_mut_ptr()
     }
 }
 
+// Data and parity blocks of a stripe, stored back to back in one aligned allocation. Erased blocks
+// keep their place in the buffer, so decoding can restore them in place. Every block may carry a
+// CRC32C of its encoded content, blocks failing it are rejected and decoded as erasures.
+#[derive(Clone)]
 pub struct BlockBuffer {
-    _buffer: Vec<u8>,
+    _buffer: AlignedMemory,
     _data_size: usize,
-    _is_data_accessible: bool,
 
-    _blocks: Vec<Option<Block>>,
-    _blocks_raw: Vec<*mut u8>,
+    _present: Vec<bool>,
+    _checksums: Vec<Option<u32>>,
+    _rejected: Vec<usize>,
+    _stripe_info: Option<StripeInfo>,
     _block_size: usize,
 
     _data_block_count: usize,
@@ -86,167 +326,368 @@ pub struct BlockBuffer {
 
 impl BlockBuffer {
 
-    pub fn new(block_size: usize,
-               data_block_count: usize,
-               parity_block_count: usize) -> BlockBuffer {
+    pub fn new(layout: &StripeLayout, parity_block_count: usize) -> Result<BlockBuffer> {
+        let mut result = BlockBuffer::_allocate(layout, parity_block_count)?;
+
+        for i in 0..result.total_block_count() {
+            result._erase_block(i, false);
+        }
+
+        Ok(result)
+    }
+
+    pub fn from_data_buffer(data_buffer: &[u8],
+                            layout: &StripeLayout,
+                            parity_block_count: usize) -> Result<BlockBuffer> {
+
+        ensure_geometry(data_buffer.len() == layout.data_size(),
+                        "data doesn't match the stripe layout")?;
+
+        let mut result = BlockBuffer::_allocate(layout, parity_block_count)?;
+        result._buffer[0..data_buffer.len()].copy_from_slice(data_buffer);
+
+        Ok(result)
+    }
+
+    pub fn from_blocks(blocks: &[Block],
+                       layout: &StripeLayout,
+                       parity_block_count: usize) -> Result<BlockBuffer> {
+
+        let block_size = layout.block_size();
+        let block_count = layout.data_block_count() + parity_block_count;
+
+        ensure_geometry(blocks.iter().all(|b| b.data().len() == block_size),
+                        "blocks don't match the block size")?;
+        ensure_geometry(blocks.iter().all(|b| b.id() < block_count), "block id out of range")?;
 
-        let mut data_buffer = vec![0u8; data_block_count * block_size];
+        let mut result = BlockBuffer::new(layout, parity_block_count)?;
 
-        let mut result = BlockBuffer::from_data_buffer(
-            data_buffer,
-            block_size,
-            data_block_count,
-            parity_block_count
-        );
+        for block in blocks.iter() {
+            ensure_geometry(result.is_erased(block.id()), "duplicate block id")?;
 
-        for i in 0..result.block_size() as usize {
-            result.erase_block(i, false);
+            result.write_block(block.id(), block.data())?;
+            result.mark_block_as_restored(block.id())?;
+            result._checksums[block.id()] = block.checksum();
         }
 
-        result
+        Ok(result)
     }
 
-    pub fn from_data_buffer(mut data_buffer: Vec<u8>,
-                            block_size: usize,
-                            data_block_count: usize,
-                            parity_block_count: usize) -> BlockBuffer {
+    // Rebuilds a stripe from blocks that know their stripe, e.g. deserialized ones. Any k blocks
+    // of the stripe are enough to decode it.
+    pub fn from_stripe_blocks(blocks: &[Block]) -> Result<BlockBuffer> {
+        let info = match blocks.first().and_then(|block| block.stripe_info()) {
+            Some(info) => *info,
+            None => return Err(CodecError::InvalidBlockFormat("blocks don't know their stripe"))
+        };
 
-        assert!(data_buffer.len() <= block_size * data_block_count);
+        ensure_format(blocks.iter().all(|block| block.stripe_info() == Some(&info)),
+                      "blocks belong to different stripes")?;
 
-        let original_data_size = data_buffer.len();
-        let total_block_count = data_block_count + parity_block_count;
-        let desired_buffer_size = total_block_count * block_size;
-        let padding_size = desired_buffer_size - original_data_size;
+        let mut result = BlockBuffer::from_blocks(blocks, &info.layout()?, info.parity_block_count)?;
+        result._stripe_info = Some(info);
 
-        data_buffer.append(&mut vec![0u8; padding_size]);
-        data_buffer.shrink_to_fit();
+        Ok(result)
+    }
 
-        let mut blocks_raw = data_buffer.chunks_mut(block_size)
-            .map(|c| c.as_mut_ptr())
-            .collect::<Vec<_>>();
+    pub fn deserialize<B: AsRef<[u8]>>(blocks: &[B]) -> Result<BlockBuffer> {
+        let blocks = blocks.iter()
+            .map(|bytes| Block::deserialize(bytes.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
 
-        let blocks = blocks_raw.iter_mut().zip(0..)
-            .map(|(p, i)| Some(Block::for_buffer_view(i, *p, block_size)))
-            .collect::<Vec<_>>();
+        BlockBuffer::from_stripe_blocks(&blocks)
+    }
 
-        BlockBuffer {
-            _buffer: data_buffer,
-            _data_size: original_data_size,
-            _is_data_accessible: true,
-            _blocks: blocks,
-            _blocks_raw: blocks_raw,
-            _block_size: block_size,
-            _data_block_count: data_block_count,
+    fn _allocate(layout: &StripeLayout, parity_block_count: usize) -> Result<BlockBuffer> {
+        let total_block_count = layout.data_block_count() + parity_block_count;
+        let buffer_size = total_block_count.checked_mul(layout.block_size())
+            .ok_or(CodecError::InvalidGeometry("buffer is too large"))?;
+
+        Ok(BlockBuffer {
+            _buffer: AlignedMemory::zeroed(buffer_size)?,
+            _data_size: layout.data_size(),
+            _present: vec![true; total_block_count],
+            _checksums: vec![None; total_block_count],
+            _rejected: Vec::new(),
+            _stripe_info: None,
+            _block_size: layout.block_size(),
+            _data_block_count: layout.data_block_count(),
             _parity_block_count: parity_block_count
+        })
+    }
+
+    #[cfg(feature = "jerasure")]
+    pub fn raw_pointers(&mut self) -> RawBlockPointers {
+        let block_size = self._block_size;
+
+        RawBlockPointers {
+            _pointers: self._buffer.chunks_mut(block_size).map(|c| c.as_mut_ptr()).collect(),
+            _data_block_count: self._data_block_count,
+            _buffer: PhantomData
         }
     }
 
-    pub fn from_blocks(blocks: &[Block],
-                       block_size: usize,
-                       data_block_count: usize,
-                       parity_block_count: usize,
-                       data_size: usize) -> BlockBuffer {
+    pub fn buffer(&self) -> &[u8] {
+        &self._buffer[..]
+    }
 
-        let block_count = data_block_count + parity_block_count;
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self._buffer[..]
+    }
 
-        assert!(blocks.iter().all(|b| b.data().len() == block_size));
-        assert!(blocks.len() <= block_count);
+    pub fn block_size(&self) -> usize {
+        self._block_size
+    }
 
-        let mut buffer = vec![0u8; block_count * block_size];
-        buffer.shrink_to_fit();
+    pub fn data_size(&self) -> usize {
+        self._data_size
+    }
 
-        let blocks_raw = buffer.chunks_mut(block_size)
-            .map(|c| c.as_mut_ptr())
-            .collect::<Vec<_>>();
+    // Blocks outside of the stripe are reported the same way as erased ones
+    pub fn block(&self, id: usize) -> Option<&[u8]> {
+        match self._present.get(id) {
+            Some(&true) => Some(&self._buffer[self._block_range(id)]),
+            _           => None
+        }
+    }
 
-        let mut block_opts = blocks_raw.iter().map(|_| None).collect::<Vec<Option<Block>>>();
+    pub fn block_mut(&mut self, id: usize) -> Option<&mut [u8]> {
+        match self._present.get(id) {
+            Some(&true) => {
+                let range = self._block_range(id);
+                Some(&mut self._buffer[range])
+            },
+            _ => None
+        }
+    }
 
-        let mut data_blocks_found = 0;
+    pub fn blocks<'a>(&'a self) -> impl Iterator<Item = Option<&'a [u8]>> + 'a {
+        (0..self.total_block_count()).map(move |id| self.block(id))
+    }
 
-        for block in blocks.iter() {
-            unsafe {
-                assert!(block_opts[block.id()].is_none());
+    pub fn to_block(&self, id: usize) -> Option<Block> {
+        self.block(id).map(|data| Block {
+            _id: id,
+            _data: data.to_vec(),
+            _checksum: self._checksums[id],
+            _stripe_info: self._stripe_info
+        })
+    }
 
-                if block.id() < data_block_count {
-                    data_blocks_found += 1;
-                }
+    pub fn stripe_info(&self) -> Option<&StripeInfo> {
+        self._stripe_info.as_ref()
+    }
 
-                let mut new_block = Block::for_buffer_view(
-                    block.id(),
-                    buffer.as_mut_ptr().offset((block.id() * block_size) as isize),
-                    block_size
-                );
+    pub fn set_stripe_info(&mut self, info: StripeInfo) {
+        self._stripe_info = Some(info);
+    }
 
-                new_block.clone_from_slice(block.data());
+    // Codec::encode() numbers every stripe 0, callers encoding one stripe at a time renumber them
+    pub fn set_stripe_id(&mut self, stripe_id: u64) {
+        if let Some(ref mut info) = self._stripe_info {
+            info.stripe_id = stripe_id;
+        }
+    }
 
-                block_opts[block.id()] = Some(new_block);
-            }
+    pub fn checksum(&self, id: usize) -> Option<u32> {
+        self._checksums.get(id).and_then(|checksum| *checksum)
+    }
+
+    // Checksums every present block, done right after encoding
+    pub fn update_checksums(&mut self) {
+        for id in 0..self.total_block_count() {
+            self._checksums[id] = self.block(id).map(crc32c::crc32c);
         }
+    }
 
-        BlockBuffer {
-            _buffer: buffer,
-            _data_size: data_size,
-            _is_data_accessible: data_blocks_found == data_block_count,
-            _blocks: block_opts,
-            _blocks_raw: blocks_raw,
-            _block_size: block_size,
-            _data_block_count: data_block_count,
-            _parity_block_count: parity_block_count
+    pub fn update_checksum(&mut self, id: usize) -> Result<()> {
+        ensure_geometry(id < self.total_block_count(), "block id out of range")?;
+        self._checksums[id] = self.block(id).map(crc32c::crc32c);
+
+        Ok(())
+    }
+
+    // Erases every present block that doesn't match its checksum and returns their ids. Rejected
+    // blocks keep their checksum, so restored blocks can be verified against it too.
+    pub fn reject_corrupted_blocks(&mut self) -> Vec<usize> {
+        let corrupted = (0..self.total_block_count())
+            .filter(|id| match (self.block(*id), self._checksums[*id]) {
+                (Some(data), Some(checksum)) => crc32c::crc32c(data) != checksum,
+                _ => false
+            })
+            .collect::<Vec<_>>();
+
+        for id in corrupted.iter() {
+            self._erase_block(*id, false);
         }
+
+        self._rejected.extend_from_slice(&corrupted);
+        corrupted
     }
 
-    pub unsafe fn data_ptrs(&mut self) -> RawBlockBuffer {
-        self._blocks_raw.as_mut_ptr()
+    // Ids of the blocks rejected so far because of a checksum mismatch
+    pub fn rejected_blocks(&self) -> &[usize] {
+        &self._rejected
     }
 
-    pub unsafe fn parity_ptrs(&mut self) -> RawBlockBuffer {
-        self._blocks_raw.as_mut_ptr().offset(self._data_block_count as isize)
+    pub fn data_block_count(&self) -> usize {
+        self._data_block_count
     }
 
-    pub fn block_size(&self) -> usize {
-        self._block_size
+    pub fn parity_block_count(&self) -> usize {
+        self._parity_block_count
     }
 
-    pub fn data_size(&self) -> usize {
-        self._data_size
+    pub fn total_block_count(&self) -> usize {
+        self._data_block_count + self._parity_block_count
+    }
+
+    pub fn is_erased(&self, id: usize) -> bool {
+        !self._present.get(id).cloned().unwrap_or(false)
+    }
+
+    pub fn erased_blocks(&self) -> Vec<usize> {
+        (0..self.total_block_count()).filter(|id| self.is_erased(*id)).collect()
     }
 
-    pub fn blocks(&self) -> &[Option<Block>] {
-        &self._blocks[..]
+    pub fn erased_block_count(&self) -> usize {
+        self._present.iter().filter(|present| !**present).count()
     }
 
     pub fn data(&self) -> Option<&[u8]> {
-        match self._is_data_accessible {
+        match self._present[0..self._data_block_count].iter().all(|present| *present) {
             true => Some(&self._buffer[0..self._data_size]),
             _    => None
         }
     }
 
-    pub fn mark_block_as_restored(&mut self, id: usize) {
-        self._blocks[id] = Some(
-            Block::for_buffer_view(id, self._blocks_raw[id], self._block_size)
-        );
+    pub fn mark_block_as_restored(&mut self, id: usize) -> Result<()> {
+        ensure_geometry(id < self.total_block_count(), "block id out of range")?;
+        self._present[id] = true;
+
+        Ok(())
     }
 
 
-    pub fn write_block(&mut self, id: usize, data: &[u8]) {
-        assert!(data.len() <= self._block_size);
+    pub fn write_block(&mut self, id: usize, data: &[u8]) -> Result<()> {
+        ensure_geometry(id < self.total_block_count(), "block id out of range")?;
+        ensure_geometry(data.len() <= self._block_size, "data doesn't fit into the block")?;
 
         let start = id * self._block_size;
         let end = start + data.len();
         self._buffer[start..end].clone_from_slice(data);
+
+        Ok(())
+    }
+
+    pub fn erase_block(&mut self, id: usize, with_zeros: bool) -> Result<()> {
+        ensure_geometry(id < self.total_block_count(), "block id out of range")?;
+        self._erase_block(id, with_zeros);
+
+        Ok(())
     }
 
-    pub fn erase_block(&mut self, id: usize, with_zeros: bool) {
-        self._blocks[id] = None;
+    fn _erase_block(&mut self, id: usize, with_zeros: bool) {
+        self._present[id] = false;
 
         if with_zeros {
-            let start = id * self._block_size;
-            let end = start + self._block_size;
+            let range = self._block_range(id);
+
+            for byte in self._buffer[range].iter_mut() {
+                *byte = 0;
+            }
+        }
+    }
+
+    fn _block_range(&self, id: usize) -> Range<usize> {
+        id * self._block_size..(id + 1) * self._block_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::super::CodecError;
+    use super::super::layout::StripeLayout;
+    use super::{Block, BlockBuffer, BLOCK_HEADER_SIZE, BLOCK_MAGIC, BLOCK_FORMAT_VERSION};
+
+    // A serialized block with the given geometry, as an attacker could send it
+    fn forged_block(k: u32, m: u32, w: u8, packet_size: u32, data_size: u64, data: &[u8]) -> Vec<u8> {
+        let mut result = vec![0u8; BLOCK_HEADER_SIZE];
+
+        result[0..4].copy_from_slice(BLOCK_MAGIC);
+        LittleEndian::write_u16(&mut result[4..6], BLOCK_FORMAT_VERSION);
+        result[6] = 1;
+        result[7] = w;
+        LittleEndian::write_u32(&mut result[8..12], k);
+        LittleEndian::write_u32(&mut result[12..16], m);
+        LittleEndian::write_u32(&mut result[16..20], packet_size);
+        LittleEndian::write_u64(&mut result[32..40], data_size);
+        LittleEndian::write_u32(&mut result[44..48], data.len() as u32);
+
+        result.extend_from_slice(data);
+        result
+    }
 
-            self._buffer[start..end].clone_from_slice(
-                vec![0u8; self._block_size].as_slice()
-            );
+    #[test]
+    fn forged_geometries_are_rejected() {
+        let forged = [
+            forged_block(u32::MAX, 2, u8::MAX, u32::MAX, u64::MAX, &[0u8; 8]),
+            forged_block(1000000, 0, 8, 1, 64000000, &[0u8; 64]),
+            forged_block(4, u32::MAX, 8, 1, 128, &[0u8; 32]),
+            forged_block(4, 2, 8, 1, u64::MAX, &[0u8; 32]),
+            forged_block(4, 2, 0, 1, 128, &[0u8; 32]),
+            forged_block(0, 2, 8, 1, 128, &[0u8; 32]),
+            forged_block(4, 2, 8, 1, 128, &[0u8; 48])
+        ];
+
+        for bytes in forged.iter() {
+            match BlockBuffer::deserialize(&[bytes]) {
+                Err(CodecError::InvalidBlockFormat(_)) | Err(CodecError::InvalidGeometry(_)) => (),
+                _ => panic!("forged block accepted")
+            }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn block_ids_outside_of_the_stripe_are_refused() {
+        let layout = StripeLayout::new(100, 4, 256).unwrap();
+        let mut buffer = BlockBuffer::from_data_buffer(&[7u8; 100], &layout, 2).unwrap();
+        buffer.update_checksums();
+
+        assert!(buffer.block(6).is_none() && buffer.block_mut(6).is_none());
+        assert!(buffer.checksum(6).is_none() && buffer.is_erased(6));
+        assert!(buffer.erase_block(6, true).is_err());
+        assert!(buffer.mark_block_as_restored(6).is_err());
+        assert!(buffer.update_checksum(6).is_err());
+
+        assert!(buffer.checksum(5).is_some() && !buffer.is_erased(5));
+    }
+
+    #[test]
+    fn truncated_and_corrupted_headers_are_rejected() {
+        let valid = forged_block(4, 2, 8, 1, 128, &[0u8; 64]);
+        assert!(Block::deserialize(&valid).is_ok());
+
+        let mut corrupted = Vec::new();
+
+        for &size in [0, 4, BLOCK_HEADER_SIZE - 1, BLOCK_HEADER_SIZE, valid.len() - 1].iter() {
+            corrupted.push(valid[..size].to_vec());
+        }
+
+        // Magic, version, codec id, block id and the LRC local group count
+        for &(offset, value) in [(0, b'X'), (4, 1), (5, 1), (6, 0xff), (20, 6), (48, 1)].iter() {
+            let mut bytes = valid.clone();
+            bytes[offset] = value;
+            corrupted.push(bytes);
+        }
+
+        for bytes in corrupted.iter() {
+            match Block::deserialize(bytes) {
+                Err(CodecError::InvalidBlockFormat(_)) => (),
+                other => panic!("corrupted header accepted: {:?}", other)
+            }
+        }
+    }
+}
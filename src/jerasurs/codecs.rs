@@ -1,84 +1,1098 @@
+use super::{Codec, CodecError, ErasureCodec, LocalGroup, Result, Technique, ensure_geometry};
+use super::matrix;
+#[cfg(feature = "jerasure")]
+use super::native;
+#[cfg(feature = "jerasure")]
+use super::native::{BitMatrix, OwnedSchedule, OwnedScheduleCache};
+use super::registry;
+use super::buffer::{BlockBuffer, StripeInfo, MAX_BLOCK_COUNT};
+use libc::c_int;
+#[cfg(feature = "jerasure")]
+use libc::{free, c_void};
+use std::sync::Arc;
+
+// Local groups of the LRC codecs created by create(), the rest of the parities are global
+static LRC_LOCAL_GROUP_COUNT: u32 = 2;
+
+// Matrix codecs work on whole w-bit words, there are no packets to speak of. A packet size of one
+// keeps Codec::chunk_size() a multiple of both sizeof(long) and w / 8, which is what
+// jerasure_matrix_encode requires from the block size.
+static MATRIX_PACKET_SIZE: i32 = 1;
+
+// Runtime selection of a codec, e.g. from the configuration of a storage pool. Techniques with a
+// fixed m or w validate them instead of ignoring the arguments.
+pub fn create(technique: Technique, k: u32, m: u32, w: u32, packet_size: usize)
+    -> Result<Box<dyn ErasureCodec>> {
+
+    match technique {
+        Technique::ReedSolVan => Ok(Box::new(reed_sol_van::create(k, m, w)?)),
+        #[cfg(feature = "jerasure")]
+        Technique::CauchyOriginal =>
+            Ok(Box::new(cauchy::create_original(k, m, w, packet_size)?)),
+        #[cfg(feature = "jerasure")]
+        Technique::CauchyGood =>
+            Ok(Box::new(cauchy::create_good(k, m, w, packet_size)?)),
+        #[cfg(feature = "jerasure")]
+        Technique::Liber8tion => {
+            ensure_geometry(m == 2 && w == 8, "liber8tion requires m = 2 and w = 8")?;
+            Ok(Box::new(liber8tion::create(k, packet_size)?))
+        },
+        #[cfg(feature = "jerasure")]
+        Technique::Liberation => {
+            ensure_geometry(m == 2, "liberation requires m = 2")?;
+            Ok(Box::new(liberation::create(k, w, packet_size)?))
+        },
+        #[cfg(feature = "jerasure")]
+        Technique::BlaumRoth => {
+            ensure_geometry(m == 2, "blaum-roth requires m = 2")?;
+            Ok(Box::new(blaum_roth::create(k, w, packet_size)?))
+        },
+        // Azure's layout, e.g. 12 + 2 + 2
+        Technique::Lrc => {
+            ensure_geometry(m > LRC_LOCAL_GROUP_COUNT, "lrc requires m > 2")?;
+            Ok(Box::new(lrc::create(k, LRC_LOCAL_GROUP_COUNT, m - LRC_LOCAL_GROUP_COUNT, w)?))
+        },
+        Technique::Replication => {
+            ensure_geometry(k == 1 && w == replication::REPLICATION_W,
+                            "replication requires k = 1 and w = 8")?;
+            let copies = m.checked_add(1)
+                .ok_or(CodecError::InvalidGeometry("too many replicas"))?;
+            Ok(Box::new(replication::create(copies)?))
+        },
+        #[cfg(not(feature = "jerasure"))]
+        _ => {
+            let _ = packet_size;
+            Err(CodecError::InvalidGeometry("technique requires the jerasure feature"))
+        }
+    }
+}
+
+// The codec that encoded a stripe, e.g. one read back with BlockBuffer::deserialize(), shared
+// through the registry. Unlike create(), LRC keeps the local group count of the stripe, LRCs with
+// a non-default group count aren't cached.
+pub fn for_stripe(info: &StripeInfo) -> Result<Arc<dyn ErasureCodec>> {
+    let (k, m, w) = (info.data_block_count as u32, info.parity_block_count as u32,
+                     info.word_size as u32);
+    let local_group_count = info.local_group_count as u32;
+
+    match info.technique {
+        Technique::Lrc if local_group_count != LRC_LOCAL_GROUP_COUNT => {
+            ensure_geometry(local_group_count <= m, "lrc requires local groups <= m")?;
+            Ok(Arc::new(lrc::create(k, local_group_count, m - local_group_count, w)?))
+        },
+        _ => registry::codec(info.technique, k, m, w, info.packet_size)
+    }
+}
+
+#[cfg(feature = "jerasure")]
+fn erasures(buffer: &BlockBuffer) -> Vec<c_int> {
+    let mut erasures = buffer.erased_blocks().iter().map(|id| *id as c_int).collect::<Vec<_>>();
+
+    erasures.push(-1);
+    erasures
+}
+
+#[cfg(feature = "jerasure")]
+fn is_prime(n: u32) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| n % d != 0)
+}
+
+// Stripes with more blocks can't be serialized, and building their matrices would take forever
+fn check_block_count(k: u32, m: u32) -> Result<()> {
+    ensure_geometry(k as u64 + m as u64 <= MAX_BLOCK_COUNT as u64, "k + m exceeds the block limit")
+}
+
+#[cfg(feature = "jerasure")]
+fn mark_erasures_as_restored(buffer: &mut BlockBuffer, erasures: &[c_int]) -> Result<()> {
+    for id in erasures[0..erasures.len() - 1].iter() {
+        buffer.mark_block_as_restored(*id as usize)?;
+    }
+
+    Ok(())
+}
+
+fn matrix_codec(technique: Technique, k: u32, m: u32, w: u32,
+                matrix: Vec<c_int>, local_groups: Vec<LocalGroup>,
+                encoding_technique: fn(&Codec, &mut BlockBuffer),
+                decoding_technique: fn(&Codec, &mut BlockBuffer) -> Result<()>) -> Codec {
+    Codec {
+        _technique: technique,
+        _k: k as c_int,
+        _w: w as c_int,
+        _m: m as c_int,
+        _packet_size: MATRIX_PACKET_SIZE,
+        _matrix: matrix,
+        _bit_matrix: Vec::new(),
+        #[cfg(feature = "jerasure")]
+        _schedule: None,
+        #[cfg(feature = "jerasure")]
+        _schedule_cache: None,
+        _local_groups: local_groups,
+        _encoding_technique: encoding_technique,
+        _decoding_technique: decoding_technique
+    }
+}
+
+// Encoding and decoding of the matrix codecs on the pure Rust Galois field backend
+fn matrix_encode(codec: &Codec, buffer: &mut BlockBuffer) {
+    let block_size = buffer.block_size();
+
+    matrix::encode(
+        codec.data_block_count(), codec.parity_block_count(), codec._w as u32,
+        &codec._matrix, buffer.buffer_mut(), block_size
+    );
+}
+
+fn matrix_decode(codec: &Codec, buffer: &mut BlockBuffer) -> Result<()> {
+    let block_size = buffer.block_size();
+    let erased = (0..buffer.total_block_count())
+        .map(|id| buffer.is_erased(id))
+        .collect::<Vec<_>>();
+
+    let result = matrix::decode(
+        codec.data_block_count(), codec.parity_block_count(), codec._w as u32,
+        &codec._matrix, &erased, buffer.buffer_mut(), block_size
+    );
+
+    if !result {
+        return Err(CodecError::BackendFailure(-1));
+    }
+
+    for id in (0..erased.len()).filter(|id| erased[*id]) {
+        buffer.mark_block_as_restored(id)?;
+    }
+
+    Ok(())
+}
+
+// Moves a malloc'd Jerasure matrix into Rust owned memory
+#[cfg(feature = "jerasure")]
+unsafe fn take_matrix(matrix: *mut c_int, size: usize) -> Result<Vec<c_int>> {
+    if matrix.is_null() {
+        return Err(CodecError::BackendFailure(-1));
+    }
+
+    let result = ::std::slice::from_raw_parts(matrix, size).to_vec();
+    free(matrix as *mut c_void);
+
+    Ok(result)
+}
+
+// Builds a codec that encodes with an XOR schedule derived from the bit matrix. Jerasure can only
+// precompute decoding schedules for m = 2, other codecs derive them lazily on every decode. The
+// precomputed ones are shared through the registry.
+#[cfg(feature = "jerasure")]
+unsafe fn schedule_codec(technique: Technique,
+                         k: c_int, m: c_int, w: c_int, packet_size: c_int,
+                         matrix: Vec<c_int>, bit_matrix: Vec<c_int>) -> Result<Codec> {
+
+    let bit_matrix_ptr = bit_matrix.as_ptr() as BitMatrix;
+
+    let schedule = OwnedSchedule::from_raw(
+        native::jerasure_smart_bitmatrix_to_schedule(k, m, w, bit_matrix_ptr)
+    ).ok_or(CodecError::BackendFailure(-1))?;
+
+    let schedule_cache = match m {
+        2 => Some(registry::schedule_cache(
+            technique, k as u32, m as u32, w as u32, packet_size as usize,
+            || OwnedScheduleCache::from_raw(
+                k, m, native::jerasure_generate_schedule_cache(k, m, w, bit_matrix_ptr, 1)
+            ).ok_or(CodecError::BackendFailure(-1))
+        )?),
+        _ => None
+    };
+
+    Ok(Codec {
+        _technique: technique,
+        _k: k,
+        _w: w,
+        _m: m,
+        _packet_size: packet_size,
+        _matrix: matrix,
+        _bit_matrix: bit_matrix,
+        _schedule: Some(schedule),
+        _schedule_cache: schedule_cache,
+        _local_groups: Vec::new(),
+        _encoding_technique: schedule_encode,
+        _decoding_technique: schedule_decode
+    })
+}
+
+#[cfg(feature = "jerasure")]
+fn schedule_encode(codec: &Codec, buffer: &mut BlockBuffer) {
+    let block_size = buffer.block_size() as c_int;
+    let schedule = codec._schedule.as_ref().expect("schedule codecs always have a schedule");
+    let mut pointers = buffer.raw_pointers();
+
+    unsafe {
+        native::jerasure_schedule_encode(
+            codec._k, codec._m, codec._w, schedule.as_ptr(),
+            pointers.data(),
+            pointers.parity(),
+            block_size,
+            codec._packet_size
+        );
+    }
+}
+
+#[cfg(feature = "jerasure")]
+fn schedule_decode(codec: &Codec, buffer: &mut BlockBuffer) -> Result<()> {
+    let mut erasures = erasures(buffer);
+    let block_size = buffer.block_size() as c_int;
+
+    unsafe {
+        let mut pointers = buffer.raw_pointers();
+
+        let result = match codec._schedule_cache {
+            Some(ref schedule_cache) => native::jerasure_schedule_decode_cache(
+                codec._k, codec._m, codec._w,
+                schedule_cache.as_ptr(),
+                erasures.as_mut_ptr(),
+                pointers.data(),
+                pointers.parity(),
+                block_size,
+                codec._packet_size
+            ),
+            None => native::jerasure_schedule_decode_lazy(
+                codec._k, codec._m, codec._w,
+                codec._bit_matrix.as_ptr() as BitMatrix,
+                erasures.as_mut_ptr(),
+                pointers.data(),
+                pointers.parity(),
+                block_size,
+                codec._packet_size,
+                1
+            )
+        };
+
+        if result != 0 {
+            return Err(CodecError::BackendFailure(result));
+        }
+    }
+
+    mark_erasures_as_restored(buffer, &erasures)
+}
+
+#[cfg(feature = "jerasure")]
 pub mod liber8tion {
-    use super::super::Codec;
+    use super::super::{Codec, Result, Technique, ensure_geometry};
     use super::super::native;
-    use super::super::buffer::BlockBuffer;
     use libc::c_int;
 
     static LIBER8TION_W: i32 = 8;
     static LIBER8TION_M: i32 = 2;
 
-    pub fn create(k: u32, packet_size: usize) -> Codec {
-        assert!(packet_size > 0);
-        assert!(k <= LIBER8TION_W as u32);
+    pub fn create(k: u32, packet_size: usize) -> Result<Codec> {
+        ensure_geometry(packet_size > 0, "packet size must be positive")?;
+        ensure_geometry(k > 0 && k <= LIBER8TION_W as u32, "liber8tion requires 0 < k <= 8")?;
+
+        let _lock = native::construction_lock();
 
         unsafe {
-            let bit_matrix = native::liber8tion_coding_bitmatrix(k as c_int);
-            let schedule = native::jerasure_smart_bitmatrix_to_schedule(
-                k as c_int, LIBER8TION_M, LIBER8TION_W, bit_matrix
-            );
+            let bit_matrix = super::take_matrix(
+                native::liber8tion_coding_bitmatrix(k as c_int),
+                (k * LIBER8TION_M as u32 * LIBER8TION_W as u32 * LIBER8TION_W as u32) as usize
+            )?;
 
-            let schedule_cache = native::jerasure_generate_schedule_cache(
-                k as c_int, LIBER8TION_M, LIBER8TION_W,
-                bit_matrix, 1
-            );
+            super::schedule_codec(
+                Technique::Liber8tion,
+                k as c_int, LIBER8TION_M, LIBER8TION_W, packet_size as c_int,
+                Vec::new(), bit_matrix
+            )
+        }
+    }
+}
 
-            Codec {
-                _k: k as c_int,
-                _w: LIBER8TION_W,
-                _m: LIBER8TION_M,
-                _packet_size: packet_size as c_int,
-                _bit_matrix: bit_matrix,
-                _schedule: schedule,
-                _schedule_cache: schedule_cache,
-                _encoding_technique: encode,
-                _decoding_technique: decode
-            }
+#[cfg(feature = "jerasure")]
+pub mod liberation {
+    use super::super::{Codec, Result, Technique, ensure_geometry};
+    use super::super::native;
+    use libc::c_int;
+
+    static LIBERATION_M: i32 = 2;
+
+    pub fn create(k: u32, w: u32, packet_size: usize) -> Result<Codec> {
+        ensure_geometry(packet_size > 0, "packet size must be positive")?;
+        ensure_geometry(w > 2 && super::is_prime(w), "liberation requires w to be a prime > 2")?;
+        ensure_geometry(k > 0 && k <= w, "liberation requires 0 < k <= w")?;
+
+        let _lock = native::construction_lock();
+
+        unsafe {
+            let bit_matrix = super::take_matrix(
+                native::liberation_coding_bitmatrix(k as c_int, w as c_int),
+                (k * LIBERATION_M as u32 * w * w) as usize
+            )?;
+
+            super::schedule_codec(
+                Technique::Liberation,
+                k as c_int, LIBERATION_M, w as c_int, packet_size as c_int,
+                Vec::new(), bit_matrix
+            )
         }
     }
+}
+
+#[cfg(feature = "jerasure")]
+pub mod blaum_roth {
+    use super::super::{Codec, Result, Technique, ensure_geometry};
+    use super::super::native;
+    use libc::c_int;
+
+    static BLAUM_ROTH_M: i32 = 2;
+
+    pub fn create(k: u32, w: u32, packet_size: usize) -> Result<Codec> {
+        ensure_geometry(packet_size > 0, "packet size must be positive")?;
+        ensure_geometry(super::is_prime(w + 1), "blaum-roth requires w + 1 to be a prime")?;
+        ensure_geometry(k > 0 && k <= w, "blaum-roth requires 0 < k <= w")?;
+
+        let _lock = native::construction_lock();
 
-    fn encode(codec: &Codec, buffer: &mut BlockBuffer) {
         unsafe {
-            native::jerasure_schedule_encode(
-                codec._k, codec._m, codec._w, codec._schedule,
-                buffer.data_ptrs(),
-                buffer.parity_ptrs(),
-                buffer.block_size() as c_int,
-                codec._packet_size
-            );
+            let bit_matrix = super::take_matrix(
+                native::blaum_roth_coding_bitmatrix(k as c_int, w as c_int),
+                (k * BLAUM_ROTH_M as u32 * w * w) as usize
+            )?;
+
+            super::schedule_codec(
+                Technique::BlaumRoth,
+                k as c_int, BLAUM_ROTH_M, w as c_int, packet_size as c_int,
+                Vec::new(), bit_matrix
+            )
         }
     }
+}
 
-    fn decode(codec: &Codec, buffer: &mut BlockBuffer) -> bool {
-        let mut erasures = Vec::<c_int>::new();
+#[cfg(feature = "jerasure")]
+pub mod cauchy {
+    use super::super::{Codec, Result, Technique, ensure_geometry};
+    use super::super::native;
+    use super::super::native::Matrix;
+    use libc::c_int;
 
-        for (block, id) in buffer.blocks().iter().zip(0..buffer.blocks().len()) {
-            if block.is_none() {
-                erasures.push(id as c_int);
-            }
+    pub fn create_original(k: u32, m: u32, w: u32, packet_size: usize) -> Result<Codec> {
+        create(Technique::CauchyOriginal, k, m, w, packet_size,
+               native::cauchy_original_coding_matrix)
+    }
+
+    pub fn create_good(k: u32, m: u32, w: u32, packet_size: usize) -> Result<Codec> {
+        create(Technique::CauchyGood, k, m, w, packet_size,
+               native::cauchy_good_general_coding_matrix)
+    }
+
+    fn create(technique: Technique, k: u32, m: u32, w: u32, packet_size: usize,
+              coding_matrix: unsafe extern fn(c_int, c_int, c_int) -> Matrix) -> Result<Codec> {
+
+        ensure_geometry(packet_size > 0, "packet size must be positive")?;
+        ensure_geometry(w > 0 && w <= 32, "cauchy requires 0 < w <= 32")?;
+        ensure_geometry(k > 0 && m > 0, "k and m must be positive")?;
+        ensure_geometry(k as u64 + m as u64 <= 1u64 << w, "cauchy requires k + m <= 2^w")?;
+        super::check_block_count(k, m)?;
+
+        let (k, m, w) = (k as c_int, m as c_int, w as c_int);
+
+        let _lock = native::construction_lock();
+
+        unsafe {
+            let matrix = super::take_matrix(coding_matrix(k, m, w), (k * m) as usize)?;
+            let bit_matrix = super::take_matrix(
+                native::jerasure_matrix_to_bitmatrix(k, m, w, matrix.as_ptr() as Matrix),
+                (k * m * w * w) as usize
+            )?;
+
+            super::schedule_codec(technique, k, m, w, packet_size as c_int, matrix, bit_matrix)
+        }
+    }
+}
+
+pub mod reed_sol_van {
+    use super::super::{Codec, Result, Technique, ensure_geometry};
+    #[cfg(feature = "jerasure")]
+    use super::super::CodecError;
+    #[cfg(feature = "jerasure")]
+    use super::super::native;
+    #[cfg(feature = "jerasure")]
+    use super::super::native::Matrix;
+    #[cfg(feature = "jerasure")]
+    use super::super::buffer::BlockBuffer;
+    use super::super::{galois, matrix};
+    #[cfg(feature = "jerasure")]
+    use libc::c_int;
+
+    fn check_geometry(k: u32, m: u32, w: u32) -> Result<()> {
+        ensure_geometry(galois::is_supported(w), "reed_sol_van requires w to be 8, 16 or 32")?;
+        ensure_geometry(k > 0 && m > 0, "k and m must be positive")?;
+        ensure_geometry(k as u64 + m as u64 <= 1u64 << w, "reed_sol_van requires k + m <= 2^w")?;
+        super::check_block_count(k, m)
+    }
+
+    #[cfg(feature = "jerasure")]
+    pub fn create(k: u32, m: u32, w: u32) -> Result<Codec> {
+        check_geometry(k, m, w)?;
+
+        let _lock = native::construction_lock();
+
+        unsafe {
+            let matrix = super::take_matrix(
+                native::reed_sol_vandermonde_coding_matrix(k as c_int, m as c_int, w as c_int),
+                (k * m) as usize
+            )?;
+
+            Ok(super::matrix_codec(Technique::ReedSolVan, k, m, w, matrix, Vec::new(),
+                                   encode, decode))
         }
+    }
 
-        erasures.push(-1);
+    #[cfg(not(feature = "jerasure"))]
+    pub fn create(k: u32, m: u32, w: u32) -> Result<Codec> {
+        create_rust(k, m, w)
+    }
+
+    // Same code on the pure Rust Galois field backend, the parity blocks are byte-identical to
+    // the ones produced by create()
+    pub fn create_rust(k: u32, m: u32, w: u32) -> Result<Codec> {
+        check_geometry(k, m, w)?;
+
+        let matrix = matrix::vandermonde_coding_matrix(k as usize, m as usize, w);
+        Ok(super::matrix_codec(Technique::ReedSolVan, k, m, w, matrix, Vec::new(),
+                               super::matrix_encode, super::matrix_decode))
+    }
+
+    #[cfg(feature = "jerasure")]
+    fn encode(codec: &Codec, buffer: &mut BlockBuffer) {
+        let block_size = buffer.block_size() as c_int;
+        let mut pointers = buffer.raw_pointers();
 
         unsafe {
-            let result = native::jerasure_schedule_decode_cache(
+            native::jerasure_matrix_encode(
                 codec._k, codec._m, codec._w,
-                codec._schedule_cache,
+                codec._matrix.as_ptr() as Matrix,
+                pointers.data(),
+                pointers.parity(),
+                block_size
+            );
+        }
+    }
+
+    #[cfg(feature = "jerasure")]
+    fn decode(codec: &Codec, buffer: &mut BlockBuffer) -> Result<()> {
+        let mut erasures = super::erasures(buffer);
+        let block_size = buffer.block_size() as c_int;
+
+        unsafe {
+            let mut pointers = buffer.raw_pointers();
+
+            // The first row of the Vandermonde coding matrix is all ones, that lets Jerasure
+            // restore a single data block with plain XOR
+            let result = native::jerasure_matrix_decode(
+                codec._k, codec._m, codec._w,
+                codec._matrix.as_ptr() as Matrix, 1,
                 erasures.as_mut_ptr(),
-                buffer.data_ptrs(),
-                buffer.parity_ptrs(),
-                buffer.block_size() as c_int,
-                codec._packet_size
+                pointers.data(),
+                pointers.parity(),
+                block_size
             );
 
             if result != 0 {
-                return false;
+                return Err(CodecError::BackendFailure(result));
+            }
+        }
+
+        super::mark_erasures_as_restored(buffer, &erasures)
+    }
+}
+
+pub mod lrc {
+    use super::super::{Codec, CodecError, LocalGroup, Result, Technique, ensure_geometry};
+    use super::super::{galois, matrix};
+    use super::super::buffer::BlockBuffer;
+    use libc::c_int;
+
+    // Azure style Local Reconstruction Code. The k data blocks are split into local groups, each
+    // protected by an XOR parity, and global Reed-Solomon parities cover all of them. Block ids
+    // are the data blocks, then the local parities, then the global ones. The local parities are
+    // the all-ones row of a Vandermonde code with one more global parity, split along the groups
+    // (a pyramid code), so any global_parity_count + 1 erasures can still be restored.
+    pub fn create(k: u32, local_group_count: u32, global_parity_count: u32, w: u32)
+        -> Result<Codec> {
+
+        ensure_geometry(galois::is_supported(w), "lrc requires w to be 8, 16 or 32")?;
+        ensure_geometry(k > 0, "k must be positive")?;
+        ensure_geometry(local_group_count > 0 && local_group_count <= k,
+                        "lrc requires 0 < local groups <= k")?;
+        ensure_geometry(k as u64 + (global_parity_count as u64) < 1u64 << w,
+                        "lrc requires k + global parities < 2^w")?;
+        super::check_block_count(k, local_group_count.saturating_add(global_parity_count))?;
+
+        let (k, l, r) = (k as usize, local_group_count as usize, global_parity_count as usize);
+        let groups = local_groups(k, l);
+        let vandermonde = matrix::vandermonde_coding_matrix(k, r + 1, w);
+
+        let mut coding_matrix = Vec::with_capacity((l + r) * k);
+
+        for group in groups.iter() {
+            coding_matrix.extend((0..k).map(|j| group.data_blocks.contains(&j) as c_int));
+        }
+
+        coding_matrix.extend_from_slice(&vandermonde[k..]);
+
+        Ok(super::matrix_codec(Technique::Lrc, k as u32, (l + r) as u32, w,
+                               coding_matrix, groups, super::matrix_encode, decode))
+    }
+
+    // Consecutive data blocks, the first k % count groups get one extra block
+    fn local_groups(k: usize, count: usize) -> Vec<LocalGroup> {
+        let mut start = 0;
+
+        (0..count)
+            .map(|i| {
+                let size = k / count + (i < k % count) as usize;
+                let group = LocalGroup {
+                    data_blocks: (start..start + size).collect(),
+                    parity_block: k + i
+                };
+
+                start += size;
+                group
+            })
+            .collect()
+    }
+
+    // Up to m erasures pass Codec::decode(), but only some patterns beyond the guaranteed ones can
+    // be restored
+    fn decode(codec: &Codec, buffer: &mut BlockBuffer) -> Result<()> {
+        let erased = buffer.erased_block_count();
+
+        super::matrix_decode(codec, buffer).map_err(|_| CodecError::TooManyErasures {
+            erased: erased,
+            tolerable: codec.parity_block_count() - codec.local_groups().len() + 1
+        })
+    }
+}
+
+pub mod replication {
+    use super::super::{Codec, Result, Technique, ensure_geometry};
+
+    pub static REPLICATION_W: u32 = 8;
+
+    // Plain copies as a degenerate erasure code: k = 1 and a coding matrix of ones, so every
+    // parity block is a copy of the data block, and any single block restores all the others
+    pub fn create(copies: u32) -> Result<Codec> {
+        ensure_geometry(copies >= 2, "replication requires at least 2 copies")?;
+        super::check_block_count(1, copies - 1)?;
+
+        let parity_block_count = copies - 1;
+
+        Ok(super::matrix_codec(Technique::Replication, 1, parity_block_count, REPLICATION_W,
+                               vec![1; parity_block_count as usize], Vec::new(),
+                               super::matrix_encode, super::matrix_decode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+
+    use rand;
+    use rand::Rng;
+
+    use super::super::{CodecError, ErasureCodec, Technique};
+    use super::{create, for_stripe, lrc, reed_sol_van, replication};
+    use super::super::matrix;
+    #[cfg(feature = "jerasure")]
+    use super::super::schedule;
+    #[cfg(feature = "jerasure")]
+    use super::super::schedule::Operation;
+    use super::super::schedule::OperationKind;
+    #[cfg(feature = "jerasure")]
+    use super::super::native;
+    #[cfg(feature = "jerasure")]
+    use super::super::native::OwnedSchedule;
+    use libc::c_int;
+    use super::super::buffer::BlockBuffer;
+
+    fn codecs() -> Vec<Box<dyn ErasureCodec>> {
+        let mut result: Vec<Box<dyn ErasureCodec>> = vec![
+            create(Technique::ReedSolVan, 4, 2, 8, 0).unwrap(),
+            create(Technique::ReedSolVan, 6, 3, 16, 0).unwrap(),
+            create(Technique::ReedSolVan, 3, 4, 32, 0).unwrap(),
+            Box::new(reed_sol_van::create_rust(5, 3, 8).unwrap()),
+            create(Technique::Lrc, 6, 4, 8, 0).unwrap(),
+            Box::new(lrc::create(7, 3, 1, 16).unwrap()),
+            create(Technique::Replication, 1, 2, 8, 0).unwrap(),
+            Box::new(replication::create(2).unwrap())
+        ];
+
+        if cfg!(feature = "jerasure") {
+            result.push(create(Technique::CauchyOriginal, 4, 3, 4, 8).unwrap());
+            result.push(create(Technique::CauchyGood, 5, 2, 8, 8).unwrap());
+            result.push(create(Technique::Liber8tion, 6, 2, 8, 8).unwrap());
+            result.push(create(Technique::Liberation, 5, 2, 7, 8).unwrap());
+            result.push(create(Technique::BlaumRoth, 4, 2, 6, 8).unwrap());
+        }
+
+        result
+    }
+
+    // Every sorted combination of count block ids out of 0..n
+    fn combinations(n: usize, count: usize) -> Vec<Vec<usize>> {
+        let mut result = Vec::new();
+        let mut current = (0..count).collect::<Vec<_>>();
+
+        if count > n {
+            return result;
+        }
+
+        loop {
+            result.push(current.clone());
+
+            let position = match (0..count).rev().find(|&i| current[i] < n - count + i) {
+                Some(position) => position,
+                None => return result
+            };
+
+            current[position] += 1;
+
+            for i in position + 1..count {
+                current[i] = current[i - 1] + 1;
+            }
+        }
+    }
+
+    fn lengths(chunk_size: usize) -> Vec<usize> {
+        vec![0, 1, chunk_size - 1, chunk_size, chunk_size + 1, 3 * chunk_size + 7,
+             rand::thread_rng().gen_range(1, 4 * chunk_size)]
+    }
+
+    // MDS codecs restore any m erasures, LRC only global parities + 1 of them in general
+    fn guaranteed_tolerance(codec: &dyn ErasureCodec) -> usize {
+        let geometry = codec.geometry();
+
+        match codec.local_groups().len() {
+            0 => geometry.parity_block_count,
+            groups => geometry.parity_block_count - groups + 1
+        }
+    }
+
+    #[test]
+    fn huge_geometries_are_rejected() {
+        for &(technique, k, m) in [(Technique::ReedSolVan, u32::MAX, 1),
+                                   (Technique::ReedSolVan, 1, u32::MAX),
+                                   (Technique::Lrc, u32::MAX, 3),
+                                   (Technique::Replication, 1, u32::MAX)].iter() {
+            match create(technique, k, m, 32, 0) {
+                Err(CodecError::InvalidGeometry(_)) => (),
+                other => panic!("{:?} {} {}: {:?}", technique, k, m, other.map(|_| ()))
+            }
+        }
+
+        assert!(lrc::create(4, 2, u32::MAX, 32).is_err());
+        assert!(replication::create(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn every_erasure_pattern_up_to_m_is_restored() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let tolerance = guaranteed_tolerance(&**codec);
+
+            for data_size in lengths(geometry.chunk_size) {
+                let input = rng.gen_iter::<u8>().take(data_size).collect::<Vec<_>>();
+                let encoded = codec.encode(&input).unwrap();
+
+                for count in 1..geometry.parity_block_count + 1 {
+                    for erasures in combinations(geometry.total_block_count(), count) {
+                        let mut buffer = encoded.clone();
+
+                        for id in erasures.iter() {
+                            buffer.erase_block(*id, true).unwrap();
+                        }
+
+                        match codec.decode(&mut buffer) {
+                            Ok(data) => assert_eq!(data, input.as_slice(),
+                                                   "{} {:?}", codec.name(), erasures),
+                            Err(CodecError::TooManyErasures { .. }) if count > tolerance => continue,
+                            Err(error) => panic!("{} {:?}: {}", codec.name(), erasures, error)
+                        }
+
+                        for id in 0..geometry.total_block_count() {
+                            assert_eq!(buffer.block(id), encoded.block(id),
+                                       "{} {:?}", codec.name(), erasures);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serialized_stripes_decode_with_the_codec_from_their_header() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let input = rng.gen_iter::<u8>().take(geometry.chunk_size + 7).collect::<Vec<_>>();
+            let encoded = codec.encode(&input).unwrap();
+
+            // The first block is lost on the way, the rest is read back from its serialized form
+            let blocks = (1..geometry.total_block_count())
+                .map(|id| encoded.to_block(id).unwrap().serialize().unwrap())
+                .collect::<Vec<_>>();
+
+            let mut buffer = BlockBuffer::deserialize(&blocks).unwrap();
+            let info = *buffer.stripe_info().unwrap();
+
+            assert_eq!(Some(&info), encoded.stripe_info(), "{}", codec.name());
+            assert!(buffer.is_erased(0));
+
+            let decoder = for_stripe(&info).unwrap();
+
+            assert_eq!(decoder.geometry(), geometry, "{}", codec.name());
+            assert_eq!(decoder.decode(&mut buffer).unwrap(), input.as_slice(), "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn any_k_serialized_blocks_rebuild_the_stripe() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let (k, m) = (geometry.data_block_count, geometry.parity_block_count);
+            let input = rng.gen_iter::<u8>().take(geometry.chunk_size + 7).collect::<Vec<_>>();
+            let encoded = codec.encode(&input).unwrap();
+
+            // The first k, the last k, and every other block as far as that goes
+            let choices: Vec<Vec<usize>> = vec![
+                (0..k).collect(),
+                (m..k + m).collect(),
+                (0..k + m).filter(|id| id % 2 == 1).chain((0..k + m).filter(|id| id % 2 == 0))
+                    .take(k).collect()
+            ];
+
+            for ids in choices.iter() {
+                let blocks = ids.iter()
+                    .map(|id| encoded.to_block(*id).unwrap().serialize().unwrap())
+                    .collect::<Vec<_>>();
+
+                let mut buffer = BlockBuffer::deserialize(&blocks).unwrap();
+                let decoder = for_stripe(buffer.stripe_info().unwrap()).unwrap();
+
+                assert_eq!(buffer.erased_block_count(), m, "{} {:?}", codec.name(), ids);
+
+                // LRC only guarantees some patterns of m erasures
+                match decoder.decode(&mut buffer) {
+                    Ok(data) => assert_eq!(data, input.as_slice(), "{} {:?}", codec.name(), ids),
+                    Err(CodecError::TooManyErasures { .. })
+                        if guaranteed_tolerance(&**codec) < m => (),
+                    Err(error) => panic!("{} {:?}: {}", codec.name(), ids, error)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stripes_round_trip_with_erasures_in_each() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let tolerance = guaranteed_tolerance(&**codec);
+
+            for &data_size in [0, 3 * geometry.chunk_size, 4 * geometry.chunk_size + 5].iter() {
+                let input = rng.gen_iter::<u8>().take(data_size).collect::<Vec<_>>();
+                let mut stripes = codec.encode_stripes(&input).unwrap();
+
+                assert_eq!(stripes.len(), cmp::max(1, (data_size + geometry.chunk_size - 1) /
+                                                      geometry.chunk_size));
+
+                for (index, stripe) in stripes.iter_mut().enumerate() {
+                    assert_eq!(stripe.stripe_info().unwrap().stripe_id, index as u64);
+
+                    // A different pattern in every stripe
+                    for id in (0..tolerance).map(|j| (index + j) % geometry.total_block_count()) {
+                        stripe.erase_block(id, true).unwrap();
+                    }
+                }
+
+                assert_eq!(codec.decode_stripes(&mut stripes).unwrap(), input,
+                           "{} {}", codec.name(), data_size);
             }
+        }
+    }
 
-            for id in erasures[0..erasures.len() - 1].iter() {
-                buffer.mark_block_as_restored(*id as usize);
+    #[test]
+    fn more_than_m_erasures_fail_cleanly() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let input = rng.gen_iter::<u8>().take(2 * geometry.chunk_size + 3).collect::<Vec<_>>();
+            let encoded = codec.encode(&input).unwrap();
+
+            for erasures in combinations(geometry.total_block_count(),
+                                         geometry.parity_block_count + 1) {
+                let mut buffer = encoded.clone();
+
+                for id in erasures.iter() {
+                    buffer.erase_block(*id, true).unwrap();
+                }
+
+                match codec.decode(&mut buffer) {
+                    Err(CodecError::TooManyErasures { erased, .. }) =>
+                        assert_eq!(erased, geometry.parity_block_count + 1),
+                    other => panic!("{} {:?}: {:?}", codec.name(), erasures, other.map(|_| ()))
+                }
+
+                assert!(erasures.iter().all(|id| buffer.is_erased(*id)));
             }
+        }
+    }
+
+    #[test]
+    fn every_single_block_is_repaired() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let input = rng.gen_iter::<u8>().take(geometry.chunk_size + 5).collect::<Vec<_>>();
+            let encoded = codec.encode(&input).unwrap();
+
+            for id in 0..geometry.total_block_count() {
+                let mut buffer = encoded.clone();
+                buffer.erase_block(id, true).unwrap();
+
+                let survivors = codec.repair(&mut buffer, &[id]).unwrap();
+
+                assert!(!survivors.contains(&id));
+                assert_eq!(buffer.block(id), encoded.block(id), "{} {}", codec.name(), id);
+            }
+        }
+    }
+
+    #[test]
+    fn lrc_repairs_read_the_local_group_only() {
+        let mut rng = rand::thread_rng();
+        let codec = lrc::create(6, 2, 2, 8).unwrap();
+        let input = rng.gen_iter::<u8>().take(codec.chunk_size() + 5).collect::<Vec<_>>();
+        let encoded = codec.encode(&input).unwrap();
+
+        let mut buffer = encoded.clone();
+        buffer.erase_block(0, true).unwrap();
+        assert_eq!(codec.repair(&mut buffer, &[0]).unwrap(), vec![1, 2, 6]);
+
+        for group in codec.local_groups().iter() {
+            for id in group.blocks() {
+                let mut buffer = encoded.clone();
+                buffer.erase_block(id, true).unwrap();
+
+                let mut expected = group.blocks();
+                expected.retain(|member| *member != id);
+
+                assert_eq!(codec.repair(&mut buffer, &[id]).unwrap(), expected, "{}", id);
+                assert_eq!(buffer.block(id), encoded.block(id), "{}", id);
+            }
+        }
+
+        // Global parities have no group and are rebuilt from any k blocks
+        for id in 8..10 {
+            let mut buffer = encoded.clone();
+            buffer.erase_block(id, true).unwrap();
+
+            let survivors = codec.repair(&mut buffer, &[id]).unwrap();
+            assert_eq!(survivors.len(), codec.data_block_count(), "{}", id);
+            assert!(!survivors.contains(&id));
+            assert_eq!(buffer.block(id), encoded.block(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn corrupted_blocks_are_rejected_and_decoded() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let input = rng.gen_iter::<u8>().take(geometry.chunk_size + 3).collect::<Vec<_>>();
+            let encoded = codec.encode(&input).unwrap();
+
+            for id in 0..geometry.total_block_count() {
+                let mut buffer = encoded.clone();
+                buffer.block_mut(id).unwrap()[1] ^= 0x20;
+
+                assert_eq!(codec.decode(&mut buffer).unwrap(), input.as_slice(),
+                           "{} {}", codec.name(), id);
+                assert_eq!(buffer.rejected_blocks(), &[id], "{} {}", codec.name(), id);
+            }
+        }
+    }
+
+    #[test]
+    fn verify_locates_a_single_corrupted_block() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter().filter(|codec| codec.geometry().parity_block_count >= 2) {
+            let geometry = codec.geometry();
+            let input = rng.gen_iter::<u8>().take(geometry.chunk_size + 3).collect::<Vec<_>>();
+            let encoded = codec.encode(&input).unwrap();
+
+            let report = codec.verify(&encoded).unwrap();
+            assert!(report.is_consistent() && report.corrupted_block.is_none(), "{}", codec.name());
+
+            for id in 0..geometry.total_block_count() {
+                let mut buffer = encoded.clone();
+                buffer.block_mut(id).unwrap()[1] ^= 0x20;
+
+                let report = codec.verify(&buffer).unwrap();
+
+                assert!(!report.is_consistent(), "{} {}", codec.name(), id);
+                assert_eq!(report.corrupted_block, Some(id), "{} {}", codec.name(), id);
+            }
+        }
+    }
+
+    #[test]
+    fn parity_updates_match_a_full_encode() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let mut input = rng.gen_iter::<u8>().take(2 * geometry.chunk_size).collect::<Vec<_>>();
+            let mut encoded = codec.encode(&input).unwrap();
+            let block_size = encoded.block_size();
+
+            for id in 0..geometry.data_block_count {
+                let old = encoded.block(id).unwrap().to_vec();
+                let new = rng.gen_iter::<u8>().take(block_size).collect::<Vec<_>>();
+
+                codec.update_parity(&mut encoded, id, &old, &new).unwrap();
+                input[id * block_size..(id + 1) * block_size].copy_from_slice(&new);
+
+                assert_eq!(encoded.buffer(), codec.encode(&input).unwrap().buffer(),
+                           "{} {}", codec.name(), id);
+
+                // A few bytes in the middle of the block
+                let offset = block_size / 2 - 3;
+                let old = encoded.block(id).unwrap()[offset..offset + 10].to_vec();
+                let new = rng.gen_iter::<u8>().take(10).collect::<Vec<_>>();
+
+                codec.update_parity_range(&mut encoded, id, offset, &old, &new).unwrap();
+                input[id * block_size + offset..id * block_size + offset + 10]
+                    .copy_from_slice(&new);
+
+                assert_eq!(encoded.buffer(), codec.encode(&input).unwrap().buffer(),
+                           "{} {} {}", codec.name(), id, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn parity_updates_with_stale_data_are_refused() {
+        let mut rng = rand::thread_rng();
+
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let input = rng.gen_iter::<u8>().take(geometry.chunk_size).collect::<Vec<_>>();
+            let mut encoded = codec.encode(&input).unwrap();
+            let block_size = encoded.block_size();
+
+            let mut old = encoded.block(0).unwrap().to_vec();
+            old[0] ^= 1;
+            let new = vec![0u8; block_size];
+
+            match codec.update_parity(&mut encoded, 0, &old, &new) {
+                Err(CodecError::StaleData) => (),
+                other => panic!("{}: {:?}", codec.name(), other)
+            }
+
+            match codec.update_parity_range(&mut encoded, 0, 0, &old[..4], &new[..4]) {
+                Err(CodecError::StaleData) => (),
+                other => panic!("{}: {:?}", codec.name(), other)
+            }
+
+            assert!(codec.verify(&encoded).unwrap().is_consistent(), "{}", codec.name());
+            assert_eq!(codec.decode(&mut encoded).unwrap(), input.as_slice(), "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn replication_copies_the_data_block() {
+        let codec = replication::create(4).unwrap();
+
+        assert_eq!(codec.coding_matrix(), Some(vec![1; 3]));
+        assert!(codec.schedule().iter().all(|operation| operation.kind == OperationKind::Copy));
+        assert_eq!(codec.xors_per_byte(), 0.0);
+    }
+
+    #[test]
+    fn bit_matrices_and_schedules_agree_with_the_matrices() {
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let (k, m, w) = (geometry.data_block_count, geometry.parity_block_count,
+                             geometry.word_size);
+            let bit_matrix = codec.coding_bit_matrix();
+
+            assert_eq!(bit_matrix.len(), m * w * k * w, "{}", codec.name());
+
+            if let Some(coding_matrix) = codec.coding_matrix() {
+                let coding_matrix = coding_matrix.iter().map(|e| *e as c_int).collect::<Vec<_>>();
+                assert_eq!(bit_matrix, matrix::to_bitmatrix(k, m, w as u32, &coding_matrix),
+                           "{}", codec.name());
+            }
+
+            let schedule = codec.schedule();
+            let xors = schedule.iter()
+                .filter(|operation| operation.kind == OperationKind::Xor)
+                .count();
+
+            assert_eq!(codec.xors_per_byte(), xors as f64 / (k * w) as f64, "{}", codec.name());
+
+            // Without the smart optimizations every bit row costs one XOR less than its ones
+            if codec.name() == Technique::ReedSolVan.name() {
+                let ones = bit_matrix.iter().filter(|bit| **bit != 0).count();
+                let rows = bit_matrix.chunks(k * w).filter(|row| row.iter().any(|bit| *bit != 0));
+
+                assert_eq!(xors, ones - rows.count(), "{}", codec.name());
+            }
+        }
+    }
+
+    #[cfg(feature = "jerasure")]
+    #[test]
+    fn unoptimized_schedules_match_jerasure() {
+        for codec in codecs().iter() {
+            let geometry = codec.geometry();
+            let (k, m, w) = (geometry.data_block_count, geometry.parity_block_count,
+                             geometry.word_size);
+            let mut bit_matrix = codec.coding_bit_matrix().iter()
+                .map(|bit| *bit as c_int)
+                .collect::<Vec<_>>();
+
+            let expected = unsafe {
+                OwnedSchedule::from_raw(native::jerasure_dumb_bitmatrix_to_schedule(
+                    k as c_int, m as c_int, w as c_int, bit_matrix.as_mut_ptr()
+                )).unwrap()
+            };
+
+            let expected = expected.operations().iter().map(Operation::from_raw).collect::<Vec<_>>();
+            assert_eq!(schedule::from_bit_matrix(k, m, w, &codec.coding_bit_matrix()), expected,
+                       "{}", codec.name());
+        }
+    }
+
+    #[cfg(feature = "jerasure")]
+    #[test]
+    fn backends_produce_identical_parity() {
+        let mut rng = rand::thread_rng();
+
+        for &(k, m, w) in [(4, 2, 8), (6, 3, 16), (3, 4, 32)].iter() {
+            let native = reed_sol_van::create(k, m, w).unwrap();
+            let rust = reed_sol_van::create_rust(k, m, w).unwrap();
+            let input = rng.gen_iter::<u8>().take(3 * native.chunk_size() + 1).collect::<Vec<_>>();
 
-            true
+            assert_eq!(native.encode(&input).unwrap().buffer(),
+                       rust.encode(&input).unwrap().buffer());
         }
     }
-}
\ No newline at end of file
+}
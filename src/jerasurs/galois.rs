@@ -0,0 +1,185 @@
+use std::sync::OnceLock;
+
+// Primitive polynomials used by gf-complete by default, so the pure Rust backend does the very
+// same arithmetic as libJerasure. The x^w term is implicit.
+static PRIM_POLY_8: u32 = 0x1d;
+static PRIM_POLY_16: u32 = 0x100b;
+static PRIM_POLY_32: u32 = 0x400007;
+
+struct LogTables {
+    log: Vec<u32>,
+    exp: Vec<u32>
+}
+
+static TABLES_8: OnceLock<LogTables> = OnceLock::new();
+static TABLES_16: OnceLock<LogTables> = OnceLock::new();
+
+impl LogTables {
+    fn generate(w: u32, prim_poly: u32) -> LogTables {
+        let size = 1usize << w;
+        let mut log = vec![0u32; size];
+        let mut exp = vec![0u32; 2 * size];
+
+        let mut value = 1u32;
+
+        for power in 0..size - 1 {
+            log[value as usize] = power as u32;
+            exp[power] = value;
+            exp[power + size - 1] = value;
+
+            value <<= 1;
+            if value & (1 << w) != 0 {
+                value = (value ^ prim_poly) & (size as u32 - 1);
+            }
+        }
+
+        LogTables { log: log, exp: exp }
+    }
+
+    fn multiply(&self, a: u32, b: u32) -> u32 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+
+        self.exp[(self.log[a as usize] + self.log[b as usize]) as usize]
+    }
+
+    fn inverse(&self, a: u32) -> u32 {
+        let order = self.exp.len() / 2 - 1;
+        self.exp[order - self.log[a as usize] as usize]
+    }
+}
+
+fn tables(w: u32) -> &'static LogTables {
+    match w {
+        8 => TABLES_8.get_or_init(|| LogTables::generate(8, PRIM_POLY_8)),
+        16 => TABLES_16.get_or_init(|| LogTables::generate(16, PRIM_POLY_16)),
+        _ => panic!("no log tables for w = {}", w)
+    }
+}
+
+fn multiply_32(mut a: u32, mut b: u32) -> u32 {
+    let mut result = 0u32;
+
+    while b != 0 {
+        if b & 1 != 0 {
+            result ^= a;
+        }
+
+        let carry = a & 0x80000000 != 0;
+        a <<= 1;
+
+        if carry {
+            a ^= PRIM_POLY_32;
+        }
+
+        b >>= 1;
+    }
+
+    result
+}
+
+fn inverse_32(a: u32) -> u32 {
+    // a^(2^32 - 2), the multiplicative group has 2^32 - 1 elements
+    let mut result = 1u32;
+    let mut base = a;
+    let mut exponent = 0xfffffffeu32;
+
+    while exponent != 0 {
+        if exponent & 1 != 0 {
+            result = multiply_32(result, base);
+        }
+
+        base = multiply_32(base, base);
+        exponent >>= 1;
+    }
+
+    result
+}
+
+pub fn is_supported(w: u32) -> bool {
+    w == 8 || w == 16 || w == 32
+}
+
+pub fn multiply(a: u32, b: u32, w: u32) -> u32 {
+    match w {
+        32 => multiply_32(a, b),
+        _ => tables(w).multiply(a, b)
+    }
+}
+
+pub fn inverse(a: u32, w: u32) -> u32 {
+    assert!(a != 0);
+
+    match w {
+        32 => inverse_32(a),
+        _ => tables(w).inverse(a)
+    }
+}
+
+pub fn divide(a: u32, b: u32, w: u32) -> u32 {
+    multiply(a, inverse(b, w), w)
+}
+
+pub fn region_xor(src: &[u8], dst: &mut [u8]) {
+    assert_eq!(src.len(), dst.len());
+
+    for (d, s) in dst.iter_mut().zip(src.iter()) {
+        *d ^= *s;
+    }
+}
+
+// Multiplies every w-bit word of src by factor and writes (or XORs, if add is set) the result
+// into dst. Words are taken in native byte order, the same way gf-complete does it.
+pub fn region_multiply(src: &[u8], factor: u32, w: u32, dst: &mut [u8], add: bool) {
+    assert_eq!(src.len(), dst.len());
+    assert_eq!(src.len() % (w as usize / 8), 0);
+
+    if factor == 1 {
+        if add {
+            region_xor(src, dst);
+        } else {
+            dst.copy_from_slice(src);
+        }
+
+        return;
+    }
+
+    match w {
+        8 => {
+            let tables = tables(8);
+
+            for (d, s) in dst.iter_mut().zip(src.iter()) {
+                let product = tables.multiply(*s as u32, factor) as u8;
+                *d = if add { *d ^ product } else { product };
+            }
+        },
+        16 => {
+            let tables = tables(16);
+
+            for (d, s) in dst.chunks_mut(2).zip(src.chunks(2)) {
+                let word = u16::from_ne_bytes([s[0], s[1]]) as u32;
+                let mut product = tables.multiply(word, factor) as u16;
+
+                if add {
+                    product ^= u16::from_ne_bytes([d[0], d[1]]);
+                }
+
+                d.copy_from_slice(&product.to_ne_bytes());
+            }
+        },
+        32 => {
+            for (d, s) in dst.chunks_mut(4).zip(src.chunks(4)) {
+                let word = u32::from_ne_bytes([s[0], s[1], s[2], s[3]]);
+                let mut product = multiply_32(word, factor);
+
+                if add {
+                    product ^= u32::from_ne_bytes([d[0], d[1], d[2], d[3]]);
+                }
+
+                d.copy_from_slice(&product.to_ne_bytes());
+            }
+        },
+        _ => panic!("unsupported word size {}", w)
+    }
+}
@@ -0,0 +1,116 @@
+use super::{CodecError, Result, ensure_geometry};
+
+// Splits an object of data_size bytes into data_block_count equally sized blocks. The padded size
+// is rounded up to a whole number of chunk_size stripes, so every block is a multiple of
+// chunk_size / data_block_count bytes, which is the packet alignment the codecs require. Empty
+// objects still take a single stripe, as a zero sized block can't be encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StripeLayout {
+    data_size: usize,
+    data_block_count: usize,
+    chunk_size: usize,
+    stripe_count: usize
+}
+
+impl StripeLayout {
+    pub fn new(data_size: usize, data_block_count: usize, chunk_size: usize) -> Result<StripeLayout> {
+        ensure_geometry(data_block_count > 0, "there must be at least one data block")?;
+        ensure_geometry(chunk_size > 0, "chunk size must be positive")?;
+        ensure_geometry(chunk_size % data_block_count == 0,
+                        "chunk size must be a multiple of the data block count")?;
+
+        let stripe_count = match data_size {
+            0 => 1,
+            _ => data_size.checked_add(chunk_size - 1)
+                .ok_or(CodecError::InvalidGeometry("data size is too large"))? / chunk_size
+        };
+
+        Ok(StripeLayout {
+            data_size: data_size,
+            data_block_count: data_block_count,
+            chunk_size: chunk_size,
+            stripe_count: stripe_count
+        })
+    }
+
+    pub fn data_size(&self) -> usize {
+        self.data_size
+    }
+
+    pub fn data_block_count(&self) -> usize {
+        self.data_block_count
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn stripe_count(&self) -> usize {
+        self.stripe_count
+    }
+
+    pub fn padded_size(&self) -> usize {
+        self.stripe_count * self.chunk_size
+    }
+
+    pub fn padding_size(&self) -> usize {
+        self.padded_size() - self.data_size
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.padded_size() / self.data_block_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand;
+    use rand::Rng;
+
+    use super::StripeLayout;
+    use super::super::codecs::reed_sol_van;
+
+    #[test]
+    fn block_size_is_aligned_and_covers_data() {
+        for &(k, chunk_size) in [(1, 8), (4, 256), (6, 24576), (10, 640)].iter() {
+            let packet_alignment = chunk_size / k;
+
+            for data_size in 0..4 * chunk_size + 1 {
+                let layout = StripeLayout::new(data_size, k, chunk_size).unwrap();
+
+                assert_eq!(layout.block_size() % packet_alignment, 0);
+                assert_eq!(layout.block_size() * k, layout.padded_size());
+                assert_eq!(layout.padded_size(), layout.data_size() + layout.padding_size());
+                assert!(layout.padding_size() < chunk_size || data_size == 0);
+                assert!(layout.block_size() > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_layouts_are_rejected() {
+        assert!(StripeLayout::new(100, 0, 256).is_err());
+        assert!(StripeLayout::new(100, 4, 0).is_err());
+        assert!(StripeLayout::new(100, 3, 256).is_err());
+        assert!(StripeLayout::new(usize::MAX, 4, 256).is_err());
+    }
+
+    #[test]
+    fn every_length_round_trips() {
+        let codec = reed_sol_van::create_rust(4, 2, 8).unwrap();
+        let mut rng = rand::thread_rng();
+
+        for data_size in 0..3 * codec.chunk_size() + 2 {
+            let input = rng.gen_iter::<u8>().take(data_size).collect::<Vec<_>>();
+            let mut encoded = codec.encode(input.as_slice()).unwrap();
+
+            let first = rng.gen_range(0, codec.total_block_count());
+            let second = rng.gen_range(0, codec.total_block_count());
+
+            encoded.erase_block(first, true).unwrap();
+            encoded.erase_block(second, true).unwrap();
+
+            assert_eq!(codec.decode(&mut encoded).unwrap(), input.as_slice());
+        }
+    }
+}
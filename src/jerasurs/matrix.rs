@@ -0,0 +1,451 @@
+use libc::c_int;
+
+use super::galois;
+
+// Port of reed_sol_big_vandermonde_distribution_matrix() from reed_sol.c. The resulting coding
+// matrix is element for element the same as what reed_sol_vandermonde_coding_matrix() returns,
+// which is what makes the parity of the two backends byte-identical.
+pub fn vandermonde_coding_matrix(k: usize, m: usize, w: u32) -> Vec<c_int> {
+    let rows = k + m;
+    let cols = k;
+    let mut dist = vec![0u32; rows * cols];
+
+    // Extended Vandermonde matrix: first row is [1, 0, .., 0], last one is [0, .., 0, 1], row i
+    // in between is [1, i, i^2, ..]
+    dist[0] = 1;
+    dist[rows * cols - 1] = 1;
+
+    for i in 1..rows - 1 {
+        let mut power = 1u32;
+
+        for j in 0..cols {
+            dist[i * cols + j] = power;
+            power = galois::multiply(power, i as u32, w);
+        }
+    }
+
+    // Column operations until the top k x k part becomes the identity
+    for i in 1..cols {
+        let pivot_row = (i..rows).find(|&row| dist[row * cols + i] != 0)
+            .expect("Vandermonde matrix should be non-singular");
+
+        if pivot_row != i {
+            for j in 0..cols {
+                dist.swap(pivot_row * cols + j, i * cols + j);
+            }
+        }
+
+        let pivot = dist[i * cols + i];
+
+        if pivot != 1 {
+            let factor = galois::divide(1, pivot, w);
+
+            for row in 0..rows {
+                dist[row * cols + i] = galois::multiply(factor, dist[row * cols + i], w);
+            }
+        }
+
+        for j in 0..cols {
+            let factor = dist[i * cols + j];
+
+            if j != i && factor != 0 {
+                for row in 0..rows {
+                    dist[row * cols + j] ^= galois::multiply(factor, dist[row * cols + i], w);
+                }
+            }
+        }
+    }
+
+    // Make the first coding row all ones by scaling the coding part of every column ..
+    for j in 0..cols {
+        let value = dist[cols * cols + j];
+
+        if value != 1 {
+            let factor = galois::divide(1, value, w);
+
+            for row in cols..rows {
+                dist[row * cols + j] = galois::multiply(factor, dist[row * cols + j], w);
+            }
+        }
+    }
+
+    // .. and the first column all ones by scaling the rest of the coding rows
+    for row in cols + 1..rows {
+        let value = dist[row * cols];
+
+        if value != 1 {
+            let factor = galois::divide(1, value, w);
+
+            for j in 0..cols {
+                dist[row * cols + j] = galois::multiply(dist[row * cols + j], factor, w);
+            }
+        }
+    }
+
+    dist[k * k..].iter().map(|e| *e as c_int).collect()
+}
+
+// Same as jerasure_matrix_to_bitmatrix(): every element e becomes a w x w block, column x of which
+// holds the bits of e * 2^x
+pub fn to_bitmatrix(k: usize, m: usize, w: u32, matrix: &[c_int]) -> Vec<u8> {
+    let w = w as usize;
+    let columns = k * w;
+    let mut result = vec![0u8; m * w * columns];
+
+    for i in 0..m {
+        for j in 0..k {
+            let mut element = matrix[i * k + j] as u32;
+
+            for x in 0..w {
+                for l in 0..w {
+                    result[(i * w + l) * columns + j * w + x] = ((element >> l) & 1) as u8;
+                }
+
+                element = galois::multiply(element, 2, w as u32);
+            }
+        }
+    }
+
+    result
+}
+
+// Gauss-Jordan elimination over GF(2^w), returns None for singular matrices
+pub fn invert(matrix: &[u32], rows: usize, w: u32) -> Option<Vec<u32>> {
+    let mut matrix = matrix.to_vec();
+    let mut inverse = vec![0u32; rows * rows];
+
+    for i in 0..rows {
+        inverse[i * rows + i] = 1;
+    }
+
+    for i in 0..rows {
+        let pivot_row = (i..rows).find(|&row| matrix[row * rows + i] != 0)?;
+
+        if pivot_row != i {
+            for j in 0..rows {
+                matrix.swap(pivot_row * rows + j, i * rows + j);
+                inverse.swap(pivot_row * rows + j, i * rows + j);
+            }
+        }
+
+        let factor = galois::inverse(matrix[i * rows + i], w);
+
+        for j in 0..rows {
+            matrix[i * rows + j] = galois::multiply(matrix[i * rows + j], factor, w);
+            inverse[i * rows + j] = galois::multiply(inverse[i * rows + j], factor, w);
+        }
+
+        for row in 0..rows {
+            let factor = matrix[row * rows + i];
+
+            if row != i && factor != 0 {
+                for j in 0..rows {
+                    matrix[row * rows + j] ^= galois::multiply(factor, matrix[i * rows + j], w);
+                    inverse[row * rows + j] ^= galois::multiply(factor, inverse[i * rows + j], w);
+                }
+            }
+        }
+    }
+
+    Some(inverse)
+}
+
+// Computes sum(coefficient * block) for the given (block id, coefficient) pairs of a buffer holding
+// consecutive blocks of block_size bytes
+fn dot_product(blocks: &[u8], block_size: usize, w: u32, terms: &[(usize, u32)]) -> Vec<u8> {
+    let mut result = vec![0u8; block_size];
+
+    for &(id, coefficient) in terms.iter() {
+        if coefficient != 0 {
+            let source = &blocks[id * block_size..(id + 1) * block_size];
+            galois::region_multiply(source, coefficient, w, result.as_mut_slice(), true);
+        }
+    }
+
+    result
+}
+
+// Equivalent of jerasure_matrix_encode(), blocks holds the k data blocks followed by the m coding
+// blocks
+pub fn encode(k: usize, m: usize, w: u32, matrix: &[c_int], blocks: &mut [u8], block_size: usize) {
+    let (data, coding) = blocks.split_at_mut(k * block_size);
+
+    for (i, target) in coding.chunks_mut(block_size).take(m).enumerate() {
+        for (j, source) in data.chunks(block_size).enumerate() {
+            let coefficient = matrix[i * k + j] as u32;
+            galois::region_multiply(source, coefficient, w, target, j != 0);
+        }
+    }
+}
+
+// Equivalent of jerasure_matrix_decode(), restores every block flagged in erased from the first k
+// surviving ones. Returns false if there aren't enough blocks left.
+pub fn decode(k: usize, m: usize, w: u32, matrix: &[c_int], erased: &[bool],
+              blocks: &mut [u8], block_size: usize) -> bool {
+
+    let candidates = (0..k + m).filter(|id| !erased[*id]).collect::<Vec<_>>();
+
+    let survivors = match select_survivors(k, w, matrix, &candidates) {
+        Some(survivors) => survivors,
+        None => return false
+    };
+
+    if (0..k).any(|id| erased[id]) {
+        let decoding_matrix = survivors.iter()
+            .flat_map(|id| generator_row(k, matrix, *id))
+            .collect::<Vec<_>>();
+
+        let inverse = match invert(&decoding_matrix, k, w) {
+            Some(inverse) => inverse,
+            None => return false
+        };
+
+        for id in (0..k).filter(|id| erased[*id]) {
+            let terms = survivors.iter().enumerate()
+                .map(|(column, survivor)| (*survivor, inverse[id * k + column]))
+                .collect::<Vec<_>>();
+
+            let restored = dot_product(blocks, block_size, w, &terms);
+            blocks[id * block_size..(id + 1) * block_size].copy_from_slice(&restored);
+        }
+    }
+
+    for id in (k..k + m).filter(|id| erased[*id]) {
+        let terms = (0..k)
+            .map(|j| (j, matrix[(id - k) * k + j] as u32))
+            .collect::<Vec<_>>();
+
+        let restored = dot_product(blocks, block_size, w, &terms);
+        blocks[id * block_size..(id + 1) * block_size].copy_from_slice(&restored);
+    }
+
+    true
+}
+
+// Row of the (k + m) x k generator matrix belonging to block id: unit rows for the data blocks on
+// top of the coding matrix
+fn generator_row(k: usize, matrix: &[c_int], id: usize) -> Vec<u32> {
+    match id < k {
+        true => (0..k).map(|j| (j == id) as u32).collect(),
+        _    => matrix[(id - k) * k..(id - k + 1) * k].iter().map(|e| *e as u32).collect()
+    }
+}
+
+// Picks the first k candidates with linearly independent generator rows, None if there aren't that
+// many. Any k blocks of an MDS code qualify, codes with local parities may have to skip some.
+pub fn select_survivors(k: usize, w: u32, matrix: &[c_int], candidates: &[usize])
+    -> Option<Vec<usize>> {
+
+    // Rows picked so far in echelon form: (pivot column, row scaled to a pivot of 1)
+    let mut basis = Vec::<(usize, Vec<u32>)>::new();
+    let mut survivors = Vec::new();
+
+    for &id in candidates.iter() {
+        if survivors.len() == k {
+            break;
+        }
+
+        let mut row = generator_row(k, matrix, id);
+
+        for &(pivot, ref basis_row) in basis.iter() {
+            let factor = row[pivot];
+
+            if factor != 0 {
+                for j in 0..k {
+                    row[j] ^= galois::multiply(factor, basis_row[j], w);
+                }
+            }
+        }
+
+        if let Some(pivot) = (0..k).find(|&j| row[j] != 0) {
+            let factor = galois::inverse(row[pivot], w);
+
+            for element in row.iter_mut() {
+                *element = galois::multiply(*element, factor, w);
+            }
+
+            basis.push((pivot, row));
+            survivors.push(id);
+        }
+    }
+
+    match survivors.len() == k {
+        true => Some(survivors),
+        _    => None
+    }
+}
+
+// Restores only the target blocks, reading nothing but the k survivors. Every target is expressed
+// as a linear combination of the survivors: generator row of the target times the inverse of the
+// survivors' generator rows.
+pub fn repair(k: usize, w: u32, matrix: &[c_int], survivors: &[usize], targets: &[usize],
+              blocks: &mut [u8], block_size: usize) -> bool {
+
+    if survivors.len() != k {
+        return false;
+    }
+
+    let survivor_rows = survivors.iter()
+        .flat_map(|id| generator_row(k, matrix, *id))
+        .collect::<Vec<_>>();
+
+    let inverse = match invert(&survivor_rows, k, w) {
+        Some(inverse) => inverse,
+        None => return false
+    };
+
+    for &target in targets.iter() {
+        let row = generator_row(k, matrix, target);
+
+        let terms = survivors.iter().enumerate()
+            .map(|(column, survivor)| {
+                let coefficient = (0..k).fold(0, |sum, j| {
+                    sum ^ galois::multiply(row[j], inverse[j * k + column], w)
+                });
+
+                (*survivor, coefficient)
+            })
+            .collect::<Vec<_>>();
+
+        let restored = dot_product(blocks, block_size, w, &terms);
+        blocks[target * block_size..(target + 1) * block_size].copy_from_slice(&restored);
+    }
+
+    true
+}
+
+// Bit rows of the (k + m)w x kw generator bit matrix belonging to block id
+fn generator_bit_rows(k: usize, w: usize, bit_matrix: &[c_int], id: usize) -> Vec<u8> {
+    let columns = k * w;
+
+    match id < k {
+        true => (0..w * columns).map(|i| (i % columns == id * w + i / columns) as u8).collect(),
+        _    => bit_matrix[(id - k) * w * columns..(id - k + 1) * w * columns]
+            .iter().map(|e| *e as u8).collect()
+    }
+}
+
+// Gauss-Jordan elimination over GF(2)
+fn invert_bitmatrix(matrix: &[u8], rows: usize) -> Option<Vec<u8>> {
+    let mut matrix = matrix.to_vec();
+    let mut inverse = vec![0u8; rows * rows];
+
+    for i in 0..rows {
+        inverse[i * rows + i] = 1;
+    }
+
+    for i in 0..rows {
+        let pivot_row = (i..rows).find(|&row| matrix[row * rows + i] != 0)?;
+
+        if pivot_row != i {
+            for j in 0..rows {
+                matrix.swap(pivot_row * rows + j, i * rows + j);
+                inverse.swap(pivot_row * rows + j, i * rows + j);
+            }
+        }
+
+        for row in 0..rows {
+            if row != i && matrix[row * rows + i] != 0 {
+                for j in 0..rows {
+                    matrix[row * rows + j] ^= matrix[i * rows + j];
+                    inverse[row * rows + j] ^= inverse[i * rows + j];
+                }
+            }
+        }
+    }
+
+    Some(inverse)
+}
+
+// Same as repair() for codecs driven by a bit matrix. Blocks consist of groups of w packets, bit
+// row i of a block corresponds to packet i of every group.
+pub fn repair_bitmatrix(k: usize, w: usize, packet_size: usize, bit_matrix: &[c_int],
+                        survivors: &[usize], targets: &[usize],
+                        blocks: &mut [u8], block_size: usize) -> bool {
+
+    let columns = k * w;
+    let group_size = w * packet_size;
+
+    if survivors.len() != k || block_size % group_size != 0 {
+        return false;
+    }
+
+    let survivor_rows = survivors.iter()
+        .flat_map(|id| generator_bit_rows(k, w, bit_matrix, *id))
+        .collect::<Vec<_>>();
+
+    let inverse = match invert_bitmatrix(&survivor_rows, columns) {
+        Some(inverse) => inverse,
+        None => return false
+    };
+
+    for &target in targets.iter() {
+        let rows = generator_bit_rows(k, w, bit_matrix, target);
+        let mut restored = vec![0u8; block_size];
+
+        for i in 0..w {
+            // Packets of the survivors that add up to packet i of the target
+            let sources = (0..columns)
+                .filter(|&column| {
+                    (0..columns).fold(0, |sum, j| {
+                        sum ^ (rows[i * columns + j] & inverse[j * columns + column])
+                    }) != 0
+                })
+                .map(|column| survivors[column / w] * block_size + (column % w) * packet_size)
+                .collect::<Vec<_>>();
+
+            for group in 0..block_size / group_size {
+                let offset = group * group_size;
+                let destination = offset + i * packet_size;
+
+                for source in sources.iter() {
+                    let source = &blocks[source + offset..source + offset + packet_size];
+                    galois::region_xor(source, &mut restored[destination..destination + packet_size]);
+                }
+            }
+        }
+
+        blocks[target * block_size..(target + 1) * block_size].copy_from_slice(&restored);
+    }
+
+    true
+}
+
+// Parity is linear in the data, so changing data block data_block by delta changes parity block i
+// by coefficient(i, data_block) * delta. Only the given parity blocks are updated.
+pub fn apply_delta(k: usize, w: u32, matrix: &[c_int], data_block: usize, delta: &[u8],
+                   parity_blocks: &[usize], blocks: &mut [u8], block_size: usize) {
+
+    for &id in parity_blocks.iter() {
+        let coefficient = matrix[(id - k) * k + data_block] as u32;
+        let target = &mut blocks[id * block_size..(id + 1) * block_size];
+
+        galois::region_multiply(delta, coefficient, w, target, true);
+    }
+}
+
+// Same as apply_delta() for codecs driven by a bit matrix: packet l of every group of a parity
+// block changes by the XOR of the delta packets selected by its bit row
+pub fn apply_delta_bitmatrix(k: usize, w: usize, packet_size: usize, bit_matrix: &[c_int],
+                             data_block: usize, delta: &[u8], parity_blocks: &[usize],
+                             blocks: &mut [u8], block_size: usize) {
+
+    let columns = k * w;
+    let group_size = w * packet_size;
+
+    for &id in parity_blocks.iter() {
+        for l in 0..w {
+            let row = ((id - k) * w + l) * columns + data_block * w;
+
+            for x in (0..w).filter(|x| bit_matrix[row + x] != 0) {
+                for group in 0..block_size / group_size {
+                    let source = group * group_size + x * packet_size;
+                    let destination = id * block_size + group * group_size + l * packet_size;
+
+                    galois::region_xor(&delta[source..source + packet_size],
+                                       &mut blocks[destination..destination + packet_size]);
+                }
+            }
+        }
+    }
+}
@@ -1,52 +1,556 @@
 mod native;
+mod galois;
+mod matrix;
+mod parallel;
+pub mod schedule;
 pub mod buffer;
 pub mod codecs;
+pub mod layout;
+pub mod stream;
+pub mod registry;
 
-use libc::{c_int, free, c_void};
+use std::fmt;
+use std::error;
+use std::result;
+#[cfg(feature = "jerasure")]
+use std::sync::Arc;
 
+use libc::c_int;
+
+#[cfg(feature = "jerasure")]
 use self::native::BitMatrix;
-use self::native::Schedule;
-use self::native::ScheduleCache;
+#[cfg(feature = "jerasure")]
+use self::native::{OwnedSchedule, OwnedScheduleCache};
 
-use self::buffer::BlockBuffer;
+use self::buffer::{BlockBuffer, StripeInfo};
+use self::layout::StripeLayout;
+use self::schedule::Operation;
 
 static WORD_SIZE: i32 = 8;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    TooManyErasures { erased: usize, tolerable: usize },
+    InvalidGeometry(&'static str),
+    DataNotAccessible,
+    BackendFailure(c_int),
+    InvalidBlockFormat(&'static str),
+    // The old data handed to a parity update isn't what the stripe holds
+    StaleData
+}
+
+pub type Result<T> = result::Result<T, CodecError>;
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecError::TooManyErasures { erased, tolerable } =>
+                write!(f, "{} blocks erased, but only {} can be restored", erased, tolerable),
+            CodecError::InvalidGeometry(reason) =>
+                write!(f, "invalid geometry: {}", reason),
+            CodecError::DataNotAccessible =>
+                write!(f, "data blocks are not accessible"),
+            CodecError::BackendFailure(code) =>
+                write!(f, "erasure coding backend failed with {}", code),
+            CodecError::InvalidBlockFormat(reason) =>
+                write!(f, "invalid serialized block: {}", reason),
+            CodecError::StaleData =>
+                write!(f, "old data doesn't match the stored block")
+        }
+    }
+}
+
+impl error::Error for CodecError {}
+
+fn ensure_geometry(condition: bool, reason: &'static str) -> Result<()> {
+    match condition {
+        true => Ok(()),
+        _    => Err(CodecError::InvalidGeometry(reason))
+    }
+}
+
+// None if the geometry is too large to address, only possible with untrusted geometries like the
+// ones read from a block header
+fn checked_chunk_size(k: usize, w: usize, packet_size: usize) -> Option<usize> {
+    k.checked_mul(w)?.checked_mul(packet_size)?.checked_mul(WORD_SIZE as usize)
+}
+
+fn chunk_size(k: usize, w: usize, packet_size: usize) -> usize {
+    checked_chunk_size(k, w, packet_size).expect("codec geometries fit into usize")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Technique {
+    ReedSolVan,
+    CauchyOriginal,
+    CauchyGood,
+    Liber8tion,
+    Liberation,
+    BlaumRoth,
+    Lrc,
+    Replication
+}
+
+impl Technique {
+    pub fn all() -> &'static [Technique] {
+        static ALL: [Technique; 8] = [
+            Technique::ReedSolVan,
+            Technique::CauchyOriginal,
+            Technique::CauchyGood,
+            Technique::Liber8tion,
+            Technique::Liberation,
+            Technique::BlaumRoth,
+            Technique::Lrc,
+            Technique::Replication
+        ];
+
+        &ALL
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Technique::ReedSolVan => "reed_sol_van",
+            Technique::CauchyOriginal => "cauchy_orig",
+            Technique::CauchyGood => "cauchy_good",
+            Technique::Liber8tion => "liber8tion",
+            Technique::Liberation => "liberation",
+            Technique::BlaumRoth => "blaum_roth",
+            Technique::Lrc => "lrc",
+            Technique::Replication => "replication"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Technique> {
+        Technique::all().iter().find(|t| t.name() == name).cloned()
+    }
+
+    // Stable identifier stored in serialized blocks, never reuse a value
+    pub fn id(&self) -> u8 {
+        match *self {
+            Technique::ReedSolVan => 1,
+            Technique::CauchyOriginal => 2,
+            Technique::CauchyGood => 3,
+            Technique::Liber8tion => 4,
+            Technique::Liberation => 5,
+            Technique::BlaumRoth => 6,
+            Technique::Lrc => 7,
+            Technique::Replication => 8
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Technique> {
+        Technique::all().iter().find(|t| t.id() == id).cloned()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub data_block_count: usize,
+    pub parity_block_count: usize,
+    pub word_size: usize,
+    pub packet_size: usize,
+    pub chunk_size: usize
+}
+
+impl Geometry {
+    pub fn total_block_count(&self) -> usize {
+        self.data_block_count + self.parity_block_count
+    }
+}
+
+// Data blocks protected by a local parity block of their own. A single lost member of the group can
+// be rebuilt from the rest of the group, without reading k blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalGroup {
+    pub data_blocks: Vec<usize>,
+    pub parity_block: usize
+}
+
+impl LocalGroup {
+    pub fn blocks(&self) -> Vec<usize> {
+        let mut blocks = self.data_blocks.clone();
+
+        blocks.push(self.parity_block);
+        blocks
+    }
+}
+
+// Outcome of Codec::verify(). Parity blocks that don't match the data are listed, and if a single
+// block can explain all of them it's reported as the corrupted one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub mismatched_parity_blocks: Vec<usize>,
+    pub corrupted_block: Option<usize>
+}
+
+impl VerifyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.mismatched_parity_blocks.is_empty()
+    }
+}
+
+// Common interface of the erasure coding schemes, storage pools pick one at runtime and use it
+// through a Box<dyn ErasureCodec>
+pub trait ErasureCodec: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn geometry(&self) -> Geometry;
+    fn local_groups(&self) -> &[LocalGroup];
+    fn encode(&self, input: &[u8]) -> Result<BlockBuffer>;
+    fn decode<'a>(&self, input: &'a mut BlockBuffer) -> Result<&'a [u8]>;
+    fn encode_stripes(&self, input: &[u8]) -> Result<Vec<BlockBuffer>>;
+    fn decode_stripes(&self, input: &mut [BlockBuffer]) -> Result<Vec<u8>>;
+    fn repair(&self, input: &mut BlockBuffer, targets: &[usize]) -> Result<Vec<usize>>;
+    fn verify(&self, input: &BlockBuffer) -> Result<VerifyReport>;
+    fn update_parity(&self, input: &mut BlockBuffer, data_block_id: usize,
+                     old: &[u8], new: &[u8]) -> Result<()>;
+    fn update_parity_range(&self, input: &mut BlockBuffer, data_block_id: usize, offset: usize,
+                           old: &[u8], new: &[u8]) -> Result<()>;
+    fn coding_matrix(&self) -> Option<Vec<u32>>;
+    fn coding_bit_matrix(&self) -> Vec<u8>;
+    fn schedule(&self) -> Vec<Operation>;
+    fn xors_per_byte(&self) -> f64;
+}
+
 pub struct Codec {
+    _technique: Technique,
     _k: c_int,
     _w: c_int,
     _m: c_int,
     _packet_size: c_int,
-    _bit_matrix: BitMatrix,
-    _schedule: Schedule,
-    _schedule_cache: ScheduleCache,
+    _matrix: Vec<c_int>,
+    _bit_matrix: Vec<c_int>,
+    #[cfg(feature = "jerasure")]
+    _schedule: Option<OwnedSchedule>,
+    #[cfg(feature = "jerasure")]
+    _schedule_cache: Option<Arc<OwnedScheduleCache>>,
+    _local_groups: Vec<LocalGroup>,
     _encoding_technique: fn(&Codec, &mut BlockBuffer),
-    _decoding_technique: fn(&Codec, &mut BlockBuffer) -> bool
+    _decoding_technique: fn(&Codec, &mut BlockBuffer) -> Result<()>
 }
 
 impl Codec {
-    pub fn encode(&self, input: &[u8]) -> BlockBuffer {
-        let input_vec = input.to_vec();
-        let padding_size = input_vec.len() % self.chunk_size();
-        let block_size = (input_vec.len() + padding_size) as usize / self.data_block_count();
+    pub fn encode(&self, input: &[u8]) -> Result<BlockBuffer> {
+        self._encode(input, 0)
+    }
+
+    fn _encode(&self, input: &[u8], stripe_id: u64) -> Result<BlockBuffer> {
+        let layout = self.layout(input.len())?;
 
         let mut result = BlockBuffer::from_data_buffer(
-            input_vec, block_size,
-            self.data_block_count(),
+            input, &layout,
             self.parity_block_count()
-        );
+        )?;
 
         (self._encoding_technique)(self, &mut result);
+        result.update_checksums();
+        result.set_stripe_info(StripeInfo {
+            technique: self._technique,
+            data_block_count: self.data_block_count(),
+            parity_block_count: self.parity_block_count(),
+            word_size: self._w as usize,
+            packet_size: self._packet_size as usize,
+            stripe_id: stripe_id,
+            data_size: input.len(),
+            local_group_count: self._local_groups.len()
+        });
+
+        Ok(result)
+    }
+
+    // Blocks failing their checksum are erased first, see BlockBuffer::rejected_blocks()
+    pub fn decode<'a>(&self, input: &'a mut BlockBuffer) -> Result<&'a [u8]> {
+        self._restore_erasures(input)?;
+
+        input.data().ok_or(CodecError::DataNotAccessible)
+    }
+
+    // Splits the input into chunk_size stripes, the same way stream::encode() does, and encodes
+    // them in parallel. The stripes are returned in order, numbered from 0.
+    pub fn encode_stripes(&self, input: &[u8]) -> Result<Vec<BlockBuffer>> {
+        let stripes = match input.is_empty() {
+            true => vec![input],
+            _    => input.chunks(self.chunk_size()).collect()
+        };
 
-        result
+        parallel::map(stripes.into_iter().enumerate(), |(stripe_id, stripe)| {
+            self._encode(stripe, stripe_id as u64)
+        }).into_iter().collect()
     }
 
-    pub fn decode<'a>(&self, input: &'a mut BlockBuffer) -> Option<&'a [u8]> {
-        if !(self._decoding_technique)(self, input) {
-            return None;
+    // Decodes the stripes of encode_stripes() in parallel and returns the concatenated data
+    pub fn decode_stripes(&self, input: &mut [BlockBuffer]) -> Result<Vec<u8>> {
+        let data_size = input.iter().map(|stripe| stripe.data_size()).sum();
+        let decoded = parallel::map(input.iter_mut(), |stripe| self.decode(stripe));
+
+        let mut result = Vec::with_capacity(data_size);
+
+        for data in decoded.into_iter() {
+            result.extend_from_slice(data?);
+        }
+
+        Ok(result)
+    }
+
+    // Rebuilds only the target blocks, other erased blocks stay erased. Returns the ids of the
+    // surviving blocks that were read, data blocks are preferred as they are cheaper to combine.
+    // Targets with an intact local group are rebuilt from that group only.
+    pub fn repair(&self, input: &mut BlockBuffer, targets: &[usize]) -> Result<Vec<usize>> {
+        self._check_buffer(input)?;
+
+        ensure_geometry(targets.iter().all(|id| *id < self.total_block_count()),
+                        "block id out of range")?;
+
+        if targets.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        input.reject_corrupted_blocks();
+
+        let available = (0..self.total_block_count())
+            .filter(|id| !input.is_erased(*id) && !targets.contains(id))
+            .collect::<Vec<_>>();
+
+        if let Some(survivors) = self._repair_locally(input, targets, &available) {
+            return Ok(survivors);
+        }
+
+        // Any k blocks of the bit matrix codecs will do, those are all MDS
+        let survivors = match self._bit_matrix.is_empty() {
+            true => matrix::select_survivors(
+                self.data_block_count(), self._w as u32, &self._matrix, &available
+            ),
+            _    => match available.len() >= self.data_block_count() {
+                true => Some(available[0..self.data_block_count()].to_vec()),
+                _    => None
+            }
+        };
+
+        let survivors = survivors.ok_or(CodecError::TooManyErasures {
+            erased: self.total_block_count() - available.len(),
+            tolerable: self.parity_block_count()
+        })?;
+
+        let block_size = input.block_size();
+
+        let repaired = if self._bit_matrix.is_empty() {
+            matrix::repair(
+                self.data_block_count(), self._w as u32, &self._matrix,
+                &survivors, targets, input.buffer_mut(), block_size
+            )
+        } else {
+            matrix::repair_bitmatrix(
+                self.data_block_count(), self._w as usize, self._packet_size as usize,
+                &self._bit_matrix, &survivors, targets, input.buffer_mut(), block_size
+            )
+        };
+
+        if !repaired {
+            return Err(CodecError::BackendFailure(-1));
+        }
+
+        for id in targets.iter() {
+            input.mark_block_as_restored(*id)?;
         }
-        
-        input.data()
+
+        Ok(survivors)
+    }
+
+    // Overwrites a data block with new and patches the parity with the difference to old, instead
+    // of encoding the whole stripe again. Both have to be whole blocks. Erased parity blocks are
+    // left alone, they are rebuilt from the new data anyway. If the data block is present, old has
+    // to match it, a wrong old would silently corrupt the parity.
+    pub fn update_parity(&self, input: &mut BlockBuffer, data_block_id: usize,
+                         old: &[u8], new: &[u8]) -> Result<()> {
+
+        ensure_geometry(old.len() == input.block_size() && new.len() == input.block_size(),
+                        "old and new data must be whole blocks")?;
+
+        self.update_parity_range(input, data_block_id, 0, old, new)
+    }
+
+    // Same as update_parity(), for the bytes of the data block starting at offset, so small writes
+    // don't need the whole block. Only whole blocks can replace an erased data block.
+    pub fn update_parity_range(&self, input: &mut BlockBuffer, data_block_id: usize, offset: usize,
+                               old: &[u8], new: &[u8]) -> Result<()> {
+
+        self._check_buffer(input)?;
+
+        let block_size = input.block_size();
+
+        ensure_geometry(data_block_id < self.data_block_count(), "data block id out of range")?;
+        ensure_geometry(old.len() == new.len(), "old and new data must have the same length")?;
+        ensure_geometry(offset <= block_size && new.len() <= block_size - offset,
+                        "range doesn't fit into the block")?;
+
+        let range = offset..offset + new.len();
+
+        match input.block(data_block_id) {
+            Some(stored) if &stored[range.clone()] != old => return Err(CodecError::StaleData),
+            Some(_) => (),
+            None => ensure_geometry(new.len() == block_size,
+                                    "erased data blocks can only be replaced as a whole")?
+        }
+
+        // Zero outside of the range, so the rest of the parity stays untouched
+        let mut delta = vec![0u8; block_size];
+        delta[range.clone()].copy_from_slice(old);
+        galois::region_xor(new, &mut delta[range.clone()]);
+
+        let parity_blocks = (self.data_block_count()..self.total_block_count())
+            .filter(|id| !input.is_erased(*id))
+            .collect::<Vec<_>>();
+
+        if self._bit_matrix.is_empty() {
+            matrix::apply_delta(
+                self.data_block_count(), self._w as u32, &self._matrix,
+                data_block_id, &delta, &parity_blocks, input.buffer_mut(), block_size
+            );
+        } else {
+            matrix::apply_delta_bitmatrix(
+                self.data_block_count(), self._w as usize, self._packet_size as usize,
+                &self._bit_matrix, data_block_id, &delta, &parity_blocks,
+                input.buffer_mut(), block_size
+            );
+        }
+
+        match input.block_mut(data_block_id) {
+            Some(block) => block[range].copy_from_slice(new),
+            None => {
+                input.write_block(data_block_id, new)?;
+                input.mark_block_as_restored(data_block_id)?;
+            }
+        }
+
+        for id in parity_blocks.into_iter().chain(Some(data_block_id)) {
+            input.update_checksum(id)?;
+        }
+
+        Ok(())
+    }
+
+    // Recomputes the parity of a complete stripe and compares it with the stored one. A single bad
+    // block is located by erasing every block in turn: only restoring the bad one gives a
+    // consistent stripe. That needs m >= 2, with a single parity every block would qualify.
+    pub fn verify(&self, input: &BlockBuffer) -> Result<VerifyReport> {
+        self._check_buffer(input)?;
+
+        ensure_geometry(input.erased_block_count() == 0,
+                        "verification needs every block of the stripe")?;
+
+        let mismatched_parity_blocks = self._parity_mismatches(input);
+
+        if mismatched_parity_blocks.is_empty() || self.parity_block_count() < 2 {
+            return Ok(VerifyReport {
+                mismatched_parity_blocks: mismatched_parity_blocks,
+                corrupted_block: None
+            });
+        }
+
+        let mut candidates = Vec::new();
+
+        for id in 0..self.total_block_count() {
+            let mut trial = input.clone();
+            trial.erase_block(id, true)?;
+
+            if (self._decoding_technique)(self, &mut trial).is_ok() &&
+               self._parity_mismatches(&trial).is_empty() {
+                candidates.push(id);
+            }
+        }
+
+        Ok(VerifyReport {
+            mismatched_parity_blocks: mismatched_parity_blocks,
+            corrupted_block: match candidates.len() {
+                1 => Some(candidates[0]),
+                _ => None
+            }
+        })
+    }
+
+    fn _parity_mismatches(&self, input: &BlockBuffer) -> Vec<usize> {
+        let mut recomputed = input.clone();
+        (self._encoding_technique)(self, &mut recomputed);
+
+        (self.data_block_count()..self.total_block_count())
+            .filter(|id| recomputed.block(*id) != input.block(*id))
+            .collect()
+    }
+
+    // Local parities are plain XOR of their group, so every target is the XOR of the rest of its
+    // group. Only done if all targets have an intact group, otherwise the global path is cheaper.
+    fn _repair_locally(&self, input: &mut BlockBuffer, targets: &[usize],
+                       available: &[usize]) -> Option<Vec<usize>> {
+
+        let groups = targets.iter()
+            .map(|target| {
+                self._local_groups.iter().find(|group| {
+                    let blocks = group.blocks();
+                    blocks.contains(target) &&
+                        blocks.iter().all(|id| id == target || available.contains(id))
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut survivors = Vec::<usize>::new();
+
+        for (&target, group) in targets.iter().zip(groups.into_iter()) {
+            let mut restored = vec![0u8; input.block_size()];
+
+            for id in group.blocks().into_iter().filter(|id| *id != target) {
+                galois::region_xor(input.block(id)?, restored.as_mut_slice());
+
+                if !survivors.contains(&id) {
+                    survivors.push(id);
+                }
+            }
+
+            input.write_block(target, restored.as_slice()).ok()?;
+            input.mark_block_as_restored(target).ok()?;
+        }
+
+        survivors.sort();
+        Some(survivors)
+    }
+
+    pub fn technique(&self) -> Technique {
+        self._technique
+    }
+
+    pub fn local_groups(&self) -> &[LocalGroup] {
+        &self._local_groups
+    }
+
+    pub fn layout(&self, data_size: usize) -> Result<StripeLayout> {
+        StripeLayout::new(data_size, self.data_block_count(), self.chunk_size())
+    }
+
+    fn _check_buffer(&self, input: &BlockBuffer) -> Result<()> {
+        ensure_geometry(
+            input.data_block_count() == self.data_block_count() &&
+            input.parity_block_count() == self.parity_block_count(),
+            "block buffer was made for a different codec"
+        )?;
+
+        ensure_geometry(
+            input.block_size() % (self.chunk_size() / self.data_block_count()) == 0,
+            "block size isn't aligned to the codec"
+        )
+    }
+
+    fn _restore_erasures(&self, input: &mut BlockBuffer) -> Result<()> {
+        self._check_buffer(input)?;
+        input.reject_corrupted_blocks();
+
+        let erased = input.erased_block_count();
+
+        if erased > self.parity_block_count() {
+            return Err(CodecError::TooManyErasures {
+                erased: erased,
+                tolerable: self.parity_block_count()
+            });
+        }
+
+        (self._decoding_technique)(self, input)
     }
 
     pub fn data_block_count(&self) -> usize {
@@ -62,13 +566,58 @@ impl Codec {
     }
 
     pub fn chunk_size(&self) -> usize {
-        (self._k * self._w * self._packet_size * WORD_SIZE) as usize
+        chunk_size(self.data_block_count(), self._w as usize, self._packet_size as usize)
+    }
+
+    // The m x k coding matrix over GF(2^w) in row major order. Codecs defined by their bit matrix
+    // only, like liber8tion, have none.
+    pub fn coding_matrix(&self) -> Option<Vec<u32>> {
+        match self._matrix.is_empty() {
+            true => None,
+            _    => Some(self._matrix.iter().map(|e| *e as u32).collect())
+        }
     }
 
+    // The mw x kw coding bit matrix in row major order, matrix codecs get theirs derived from the
+    // coding matrix
+    pub fn coding_bit_matrix(&self) -> Vec<u8> {
+        match self._bit_matrix.is_empty() {
+            true => matrix::to_bitmatrix(
+                self.data_block_count(), self.parity_block_count(), self._w as u32, &self._matrix
+            ),
+            _    => self._bit_matrix.iter().map(|e| *e as u8).collect()
+        }
+    }
+
+    // The encoding schedule Jerasure executes. Matrix codecs don't run one, for comparison they
+    // report the schedule of their bit matrix without any of the smart optimizations.
+    pub fn schedule(&self) -> Vec<Operation> {
+        #[cfg(feature = "jerasure")]
+        {
+            if let Some(ref schedule) = self._schedule {
+                return schedule.operations().iter().map(Operation::from_raw).collect();
+            }
+        }
+
+        schedule::from_bit_matrix(
+            self.data_block_count(), self.parity_block_count(), self._w as usize,
+            &self.coding_bit_matrix()
+        )
+    }
+
+    pub fn xors_per_byte(&self) -> f64 {
+        schedule::xors_per_byte(self.data_block_count(), self._w as usize, &self.schedule())
+    }
+
+    #[cfg(feature = "jerasure")]
     pub fn print_bit_matrix(&self) {
+        if self._bit_matrix.is_empty() {
+            return;
+        }
+
         unsafe {
             native::jerasure_print_bitmatrix(
-                self._bit_matrix,
+                self._bit_matrix.as_ptr() as BitMatrix,
                 self._w * self._m,
                 self._w * self._k,
                 self._w
@@ -77,12 +626,72 @@ impl Codec {
     }
 }
 
-impl Drop for Codec {
-    fn drop(&mut self) {
-        unsafe {
-            free(self._bit_matrix as *mut c_void);
-            native::jerasure_free_schedule(self._schedule);
-            native::jerasure_free_schedule_cache(self._k, self._m, self._schedule_cache);
+impl ErasureCodec for Codec {
+    fn name(&self) -> &'static str {
+        self._technique.name()
+    }
+
+    fn geometry(&self) -> Geometry {
+        Geometry {
+            data_block_count: self.data_block_count(),
+            parity_block_count: self.parity_block_count(),
+            word_size: self._w as usize,
+            packet_size: self._packet_size as usize,
+            chunk_size: self.chunk_size()
         }
     }
+
+    fn local_groups(&self) -> &[LocalGroup] {
+        Codec::local_groups(self)
+    }
+
+    fn encode(&self, input: &[u8]) -> Result<BlockBuffer> {
+        Codec::encode(self, input)
+    }
+
+    fn decode<'a>(&self, input: &'a mut BlockBuffer) -> Result<&'a [u8]> {
+        Codec::decode(self, input)
+    }
+
+    fn encode_stripes(&self, input: &[u8]) -> Result<Vec<BlockBuffer>> {
+        Codec::encode_stripes(self, input)
+    }
+
+    fn decode_stripes(&self, input: &mut [BlockBuffer]) -> Result<Vec<u8>> {
+        Codec::decode_stripes(self, input)
+    }
+
+    fn repair(&self, input: &mut BlockBuffer, targets: &[usize]) -> Result<Vec<usize>> {
+        Codec::repair(self, input, targets)
+    }
+
+    fn verify(&self, input: &BlockBuffer) -> Result<VerifyReport> {
+        Codec::verify(self, input)
+    }
+
+    fn update_parity(&self, input: &mut BlockBuffer, data_block_id: usize,
+                     old: &[u8], new: &[u8]) -> Result<()> {
+        Codec::update_parity(self, input, data_block_id, old, new)
+    }
+
+    fn update_parity_range(&self, input: &mut BlockBuffer, data_block_id: usize, offset: usize,
+                           old: &[u8], new: &[u8]) -> Result<()> {
+        Codec::update_parity_range(self, input, data_block_id, offset, old, new)
+    }
+
+    fn coding_matrix(&self) -> Option<Vec<u32>> {
+        Codec::coding_matrix(self)
+    }
+
+    fn coding_bit_matrix(&self) -> Vec<u8> {
+        Codec::coding_bit_matrix(self)
+    }
+
+    fn schedule(&self) -> Vec<Operation> {
+        Codec::schedule(self)
+    }
+
+    fn xors_per_byte(&self) -> f64 {
+        Codec::xors_per_byte(self)
+    }
 }
@@ -1,16 +1,35 @@
 use libc::c_int;
+#[cfg(feature = "jerasure")]
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
 pub type Schedule = *mut *mut c_int;
 pub type ScheduleCache = *mut *mut *mut c_int;
 pub type BitMatrix = *mut c_int;
+pub type Matrix = *mut c_int;
 pub type RawBlockBuffer = *mut *mut u8;
 pub type Erasures = *mut c_int;
 
+#[cfg(feature = "jerasure")]
 #[link(name = "Jerasure", kind = "static")]
 #[link(name = "gf_complete", kind = "static")]
 extern {
     pub fn liber8tion_coding_bitmatrix(k: c_int) -> BitMatrix;
 
+    pub fn liberation_coding_bitmatrix(k: c_int, w: c_int) -> BitMatrix;
+
+    pub fn blaum_roth_coding_bitmatrix(k: c_int, w: c_int) -> BitMatrix;
+
+    pub fn reed_sol_vandermonde_coding_matrix(k: c_int, m: c_int, w: c_int) -> Matrix;
+
+    pub fn cauchy_original_coding_matrix(k: c_int, m: c_int, w: c_int) -> Matrix;
+
+    pub fn cauchy_good_general_coding_matrix(k: c_int, m: c_int, w: c_int) -> Matrix;
+
+    pub fn jerasure_matrix_to_bitmatrix(
+        k: c_int, m: c_int, w: c_int,
+        matrix: Matrix
+    ) -> BitMatrix;
+
     pub fn jerasure_print_bitmatrix(
         bit_matrix: BitMatrix,
         n: c_int, m: c_int, w: c_int
@@ -21,6 +40,11 @@ extern {
         bit_matrix: BitMatrix
     ) -> Schedule;
 
+    pub fn jerasure_dumb_bitmatrix_to_schedule(
+        k: c_int, m: c_int, w: c_int,
+        bit_matrix: BitMatrix
+    ) -> Schedule;
+
     pub fn jerasure_free_schedule(schedule: Schedule);
 
     pub fn jerasure_schedule_encode(
@@ -52,4 +76,143 @@ extern {
         block_size: c_int,
         packet_size: c_int
     ) -> c_int;
-}
\ No newline at end of file
+
+    pub fn jerasure_schedule_decode_lazy(
+        k: c_int, m: c_int, w: c_int,
+        bit_matrix: BitMatrix,
+        erasures: Erasures,
+        data: RawBlockBuffer,
+        coding: RawBlockBuffer,
+        block_size: c_int,
+        packet_size: c_int,
+        smart: c_int
+    ) -> c_int;
+
+    pub fn jerasure_matrix_encode(
+        k: c_int, m: c_int, w: c_int,
+        matrix: Matrix,
+        data_ptrs: RawBlockBuffer,
+        coding_ptrs: RawBlockBuffer,
+        size: c_int
+    );
+
+    pub fn jerasure_matrix_decode(
+        k: c_int, m: c_int, w: c_int,
+        matrix: Matrix,
+        row_k_ones: c_int,
+        erasures: Erasures,
+        data_ptrs: RawBlockBuffer,
+        coding_ptrs: RawBlockBuffer,
+        size: c_int
+    ) -> c_int;
+}
+
+// libJerasure sets up the Galois field of a word size on first use, and that isn't thread-safe.
+// Building a coding matrix is the first use of its word size, so every call creating one holds
+// this lock. Encoding and decoding only run on fields that are already set up.
+#[cfg(feature = "jerasure")]
+static CONSTRUCTION: Mutex<()> = Mutex::new(());
+
+#[cfg(feature = "jerasure")]
+pub fn construction_lock() -> MutexGuard<'static, ()> {
+    CONSTRUCTION.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Owned smart schedule, freed with jerasure_free_schedule()
+#[cfg(feature = "jerasure")]
+pub struct OwnedSchedule {
+    _schedule: Schedule
+}
+
+// Nothing but the owner frees the schedule, and after jerasure_smart_bitmatrix_to_schedule() has
+// built it libJerasure only reads it (jerasure_schedule_encode() and friends)
+#[cfg(feature = "jerasure")]
+unsafe impl Send for OwnedSchedule {}
+#[cfg(feature = "jerasure")]
+unsafe impl Sync for OwnedSchedule {}
+
+#[cfg(feature = "jerasure")]
+impl OwnedSchedule {
+    // Takes ownership of a schedule returned by libJerasure, None if it's NULL
+    pub unsafe fn from_raw(schedule: Schedule) -> Option<OwnedSchedule> {
+        match schedule.is_null() {
+            true => None,
+            _    => Some(OwnedSchedule { _schedule: schedule })
+        }
+    }
+
+    pub fn as_ptr(&self) -> Schedule {
+        self._schedule
+    }
+
+    // The schedule is an array of pointers to five ints, terminated by an operation starting
+    // with -1
+    pub fn operations(&self) -> Vec<[c_int; 5]> {
+        let mut result = Vec::new();
+
+        unsafe {
+            for i in 0.. {
+                let operation = *self._schedule.offset(i);
+
+                if *operation == -1 {
+                    break;
+                }
+
+                let mut copy = [0 as c_int; 5];
+                copy.copy_from_slice(::std::slice::from_raw_parts(operation, 5));
+                result.push(copy);
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(feature = "jerasure")]
+impl Drop for OwnedSchedule {
+    fn drop(&mut self) {
+        unsafe {
+            jerasure_free_schedule(self._schedule);
+        }
+    }
+}
+
+// Owned decoding schedules of every erasure pattern, freed with jerasure_free_schedule_cache()
+#[cfg(feature = "jerasure")]
+pub struct OwnedScheduleCache {
+    _schedule_cache: ScheduleCache,
+    _k: c_int,
+    _m: c_int
+}
+
+// Same as for OwnedSchedule: jerasure_schedule_decode_cache() only looks schedules up and runs
+// them, the cache is never modified after jerasure_generate_schedule_cache() returns
+#[cfg(feature = "jerasure")]
+unsafe impl Send for OwnedScheduleCache {}
+#[cfg(feature = "jerasure")]
+unsafe impl Sync for OwnedScheduleCache {}
+
+#[cfg(feature = "jerasure")]
+impl OwnedScheduleCache {
+    // Takes ownership of a schedule cache generated for k and m, None if it's NULL
+    pub unsafe fn from_raw(k: c_int, m: c_int,
+                           schedule_cache: ScheduleCache) -> Option<OwnedScheduleCache> {
+        match schedule_cache.is_null() {
+            true => None,
+            _    => Some(OwnedScheduleCache { _schedule_cache: schedule_cache, _k: k, _m: m })
+        }
+    }
+
+    pub fn as_ptr(&self) -> ScheduleCache {
+        self._schedule_cache
+    }
+}
+
+#[cfg(feature = "jerasure")]
+impl Drop for OwnedScheduleCache {
+    fn drop(&mut self) {
+        unsafe {
+            jerasure_free_schedule_cache(self._k, self._m, self._schedule_cache);
+        }
+    }
+}
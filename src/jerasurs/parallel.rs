@@ -0,0 +1,46 @@
+use std::cmp;
+use std::sync::Mutex;
+use std::thread;
+
+// Applies f to every item on all cores and returns the results in the order of the items. The
+// workers pull items one by one from a shared iterator, so uneven items balance out.
+pub fn map<I, R, F>(items: I, f: F) -> Vec<R>
+    where I: Iterator + Send, I::Item: Send, R: Send, F: Fn(I::Item) -> R + Sync {
+
+    let (item_count, _) = items.size_hint();
+    let worker_count = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let worker_count = cmp::max(1, cmp::min(worker_count, item_count));
+
+    let queue = Mutex::new(items.enumerate());
+    let f = &f;
+
+    let mut results = thread::scope(|scope| {
+        let workers = (0..worker_count)
+            .map(|_| {
+                let queue = &queue;
+
+                scope.spawn(move || {
+                    let mut results = Vec::new();
+
+                    loop {
+                        let next = queue.lock().unwrap().next();
+
+                        match next {
+                            Some((index, item)) => results.push((index, f(item))),
+                            None => break
+                        }
+                    }
+
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers.into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    results.sort_by_key(|&(index, _)| index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use super::{ErasureCodec, Result, Technique};
+use super::codecs;
+#[cfg(feature = "jerasure")]
+use super::native::OwnedScheduleCache;
+
+// Process-wide caches of everything that is expensive to build. Codecs are immutable once created,
+// so request handlers can share them freely.
+type Key = (Technique, u32, u32, u32, usize);
+
+static CODECS: OnceLock<Mutex<HashMap<Key, Arc<dyn ErasureCodec>>>> = OnceLock::new();
+#[cfg(feature = "jerasure")]
+static SCHEDULE_CACHES: OnceLock<Mutex<HashMap<Key, Arc<OwnedScheduleCache>>>> = OnceLock::new();
+
+// Looks key up, builds the value outside of the lock on a miss. Two threads missing at the same
+// time both build one, the first inserted wins. The native codecs serialize their construction
+// themselves, see native::construction_lock().
+fn get_or_create<V, F>(cache: &Mutex<HashMap<Key, Arc<V>>>, key: Key, create: F) -> Result<Arc<V>>
+    where V: ?Sized, F: FnOnce() -> Result<Arc<V>> {
+
+    if let Some(value) = cache.lock().unwrap().get(&key) {
+        return Ok(value.clone());
+    }
+
+    let value = create()?;
+    Ok(cache.lock().unwrap().entry(key).or_insert(value).clone())
+}
+
+// Same as codecs::create(), but every geometry is only created once per process
+pub fn codec(technique: Technique, k: u32, m: u32, w: u32, packet_size: usize)
+    -> Result<Arc<dyn ErasureCodec>> {
+
+    let codecs = CODECS.get_or_init(|| Mutex::new(HashMap::new()));
+
+    get_or_create(codecs, (technique, k, m, w, packet_size), || {
+        Ok(Arc::from(codecs::create(technique, k, m, w, packet_size)?))
+    })
+}
+
+// Decoding schedules of every erasure pattern take a while to generate, codecs of the same
+// geometry share them. generate() is only called on a miss.
+#[cfg(feature = "jerasure")]
+pub fn schedule_cache<F>(technique: Technique, k: u32, m: u32, w: u32, packet_size: usize,
+                         generate: F) -> Result<Arc<OwnedScheduleCache>>
+    where F: FnOnce() -> Result<OwnedScheduleCache> {
+
+    let caches = SCHEDULE_CACHES.get_or_init(|| Mutex::new(HashMap::new()));
+
+    get_or_create(caches, (technique, k, m, w, packet_size), || Ok(Arc::new(generate()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::codec;
+    use super::super::Technique;
+
+    #[test]
+    fn codecs_are_shared_per_geometry() {
+        let first = codec(Technique::ReedSolVan, 4, 2, 8, 0).unwrap();
+        let second = codec(Technique::ReedSolVan, 4, 2, 8, 0).unwrap();
+
+        assert!(Arc::ptr_eq(&first, &second));
+
+        for &(technique, k, m, w) in [(Technique::ReedSolVan, 5, 2, 8),
+                                      (Technique::ReedSolVan, 4, 3, 8),
+                                      (Technique::ReedSolVan, 4, 2, 16),
+                                      (Technique::Lrc, 4, 3, 8)].iter() {
+            let other = codec(technique, k, m, w, 0).unwrap();
+            assert!(!Arc::ptr_eq(&first, &other), "{:?} {} {} {}", technique, k, m, w);
+        }
+
+        // Failures aren't cached
+        assert!(codec(Technique::ReedSolVan, 0, 2, 8, 0).is_err());
+        assert!(codec(Technique::ReedSolVan, 0, 2, 8, 0).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use libc::c_int;
+
+// A schedule is what the bit matrix codecs execute to encode a group of w packets per block:
+// every operation copies or XORs one packet into another. Block ids are 0..k for the data and
+// k..k + m for the parity blocks, packets are numbered 0..w within the group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Copy,
+    Xor
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub source_block: usize,
+    pub source_packet: usize,
+    pub destination_block: usize,
+    pub destination_packet: usize
+}
+
+impl Operation {
+    // Jerasure's representation: source device, source packet, destination device, destination
+    // packet and 0 for copy or 1 for XOR
+    pub fn from_raw(operation: &[c_int; 5]) -> Operation {
+        Operation {
+            kind: match operation[4] {
+                0 => OperationKind::Copy,
+                _ => OperationKind::Xor
+            },
+            source_block: operation[0] as usize,
+            source_packet: operation[1] as usize,
+            destination_block: operation[2] as usize,
+            destination_packet: operation[3] as usize
+        }
+    }
+}
+
+// Same as jerasure_dumb_bitmatrix_to_schedule(): every parity packet is computed from scratch, as
+// the XOR of the data packets selected by its bit row
+pub fn from_bit_matrix(k: usize, m: usize, w: usize, bit_matrix: &[u8]) -> Vec<Operation> {
+    let columns = k * w;
+    let mut result = Vec::new();
+
+    for row in 0..m * w {
+        let mut kind = OperationKind::Copy;
+
+        for column in (0..columns).filter(|column| bit_matrix[row * columns + column] != 0) {
+            result.push(Operation {
+                kind: kind,
+                source_block: column / w,
+                source_packet: column % w,
+                destination_block: k + row / w,
+                destination_packet: row % w
+            });
+
+            kind = OperationKind::Xor;
+        }
+    }
+
+    result
+}
+
+// Every operation moves packet_size bytes, and a group holds k * w packets of data
+pub fn xors_per_byte(k: usize, w: usize, schedule: &[Operation]) -> f64 {
+    let xors = schedule.iter().filter(|operation| operation.kind == OperationKind::Xor).count();
+
+    xors as f64 / (k * w) as f64
+}
@@ -0,0 +1,214 @@
+use std::io;
+use std::io::{Read, Write};
+use std::cmp;
+
+use super::{CodecError, ErasureCodec};
+use super::buffer::{BlockBuffer, BLOCK_HEADER_SIZE};
+use super::layout::StripeLayout;
+
+// Streams are processed one chunk_size stripe at a time, so every block stream consists of
+// serialized blocks, one per stripe and numbered from 0. The decoder needs nothing but the
+// original data size to know where the blocks end and to undo the padding of the last stripe.
+
+fn codec_error(error: CodecError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn read_stripe<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error)
+        }
+    }
+
+    Ok(filled)
+}
+
+// Encodes everything from input and writes block i of every stripe to outputs[i]. Returns the
+// number of bytes read, which has to be handed over to decode().
+pub fn encode<C, R, W>(codec: &C, input: &mut R, outputs: &mut [W]) -> io::Result<u64>
+    where C: ErasureCodec + ?Sized, R: Read, W: Write {
+
+    let geometry = codec.geometry();
+
+    if outputs.len() != geometry.total_block_count() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "one output is needed per block"));
+    }
+
+    let mut stripe = vec![0u8; geometry.chunk_size];
+    let mut stripe_id = 0u64;
+    let mut total_size = 0u64;
+
+    loop {
+        let size = read_stripe(input, stripe.as_mut_slice())?;
+
+        if size == 0 {
+            break;
+        }
+
+        let mut encoded = codec.encode(&stripe[..size]).map_err(codec_error)?;
+        encoded.set_stripe_id(stripe_id);
+
+        for (id, output) in outputs.iter_mut().enumerate() {
+            if let Some(block) = encoded.to_block(id) {
+                output.write_all(&block.serialize().map_err(codec_error)?)?;
+            }
+        }
+
+        stripe_id += 1;
+        total_size += size as u64;
+
+        if size < stripe.len() {
+            break;
+        }
+    }
+
+    for output in outputs.iter_mut() {
+        output.flush()?;
+    }
+
+    Ok(total_size)
+}
+
+// Restores data_size bytes from the block streams produced by encode() and writes them to output.
+// Missing streams are passed as None, at most m of them can be missing.
+pub fn decode<C, R, W>(codec: &C, inputs: &mut [Option<R>], output: &mut W,
+                       data_size: u64) -> io::Result<u64>
+    where C: ErasureCodec + ?Sized, R: Read, W: Write {
+
+    let geometry = codec.geometry();
+
+    if inputs.len() != geometry.total_block_count() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "one input is needed per block"));
+    }
+
+    let mut remaining = data_size;
+    let mut stripe_id = 0u64;
+
+    while remaining > 0 {
+        let stripe_size = cmp::min(remaining, geometry.chunk_size as u64) as usize;
+
+        let layout = StripeLayout::new(stripe_size, geometry.data_block_count, geometry.chunk_size)
+            .map_err(codec_error)?;
+
+        let serialized_size = BLOCK_HEADER_SIZE + layout.block_size();
+        let mut blocks = Vec::<Vec<u8>>::new();
+
+        for input in inputs.iter_mut() {
+            if let Some(ref mut reader) = *input {
+                let mut data = vec![0u8; serialized_size];
+                reader.read_exact(data.as_mut_slice())?;
+                blocks.push(data);
+            }
+        }
+
+        let mut buffer = BlockBuffer::deserialize(&blocks).map_err(codec_error)?;
+
+        // Blocks of another stripe would decode to garbage without any error
+        if buffer.stripe_info().map(|info| info.stripe_id) != Some(stripe_id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stripes out of order"));
+        }
+
+        output.write_all(codec.decode(&mut buffer).map_err(codec_error)?)?;
+        stripe_id += 1;
+        remaining -= stripe_size as u64;
+    }
+
+    output.flush()?;
+
+    Ok(data_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rand;
+    use rand::Rng;
+
+    use super::{decode, encode};
+    use super::super::{ErasureCodec, Technique};
+    use super::super::buffer::{Block, BLOCK_HEADER_SIZE};
+    use super::super::codecs::create;
+
+    #[test]
+    fn streams_round_trip_with_up_to_m_missing_inputs() {
+        let mut rng = rand::thread_rng();
+        let codecs: Vec<Box<dyn ErasureCodec>> = vec![
+            create(Technique::ReedSolVan, 4, 2, 8, 0).unwrap(),
+            create(Technique::ReedSolVan, 3, 3, 16, 0).unwrap(),
+            create(Technique::Replication, 1, 2, 8, 0).unwrap()
+        ];
+
+        for codec in codecs.iter() {
+            let geometry = codec.geometry();
+
+            for &data_size in [0, geometry.chunk_size, geometry.chunk_size + 1].iter() {
+                let input = rng.gen_iter::<u8>().take(data_size).collect::<Vec<_>>();
+                let mut outputs = vec![Vec::new(); geometry.total_block_count()];
+
+                let size = encode(&**codec, &mut Cursor::new(&input), &mut outputs).unwrap();
+                assert_eq!(size, data_size as u64);
+
+                // Losing the first blocks hits the data, the expensive case
+                for missing in 0..geometry.parity_block_count + 1 {
+                    let mut inputs = outputs.iter()
+                        .enumerate()
+                        .map(|(id, output)| match id < missing {
+                            true => None,
+                            _    => Some(Cursor::new(output.as_slice()))
+                        })
+                        .collect::<Vec<_>>();
+
+                    let mut decoded = Vec::new();
+                    decode(&**codec, &mut inputs, &mut decoded, size).unwrap();
+
+                    assert_eq!(decoded, input, "{} {} {}", codec.name(), data_size, missing);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn streams_carry_numbered_stripes() {
+        let mut rng = rand::thread_rng();
+        let codec = create(Technique::ReedSolVan, 4, 2, 8, 0).unwrap();
+        let geometry = codec.geometry();
+        let serialized_size = BLOCK_HEADER_SIZE + geometry.chunk_size / geometry.data_block_count;
+
+        let input = rng.gen_iter::<u8>().take(geometry.chunk_size * 3).collect::<Vec<_>>();
+        let mut outputs = vec![Vec::new(); geometry.total_block_count()];
+        let size = encode(&*codec, &mut Cursor::new(&input), &mut outputs).unwrap();
+
+        for (id, output) in outputs.iter().enumerate() {
+            assert_eq!(output.len(), serialized_size * 3);
+
+            for (stripe_id, bytes) in output.chunks(serialized_size).enumerate() {
+                let block = Block::deserialize(bytes).unwrap();
+
+                assert_eq!(block.id(), id);
+                assert_eq!(block.stripe_info().unwrap().stripe_id, stripe_id as u64);
+            }
+        }
+
+        // A stream whose first two stripes were swapped is refused
+        let mut swapped = outputs[0][serialized_size..serialized_size * 2].to_vec();
+        swapped.extend_from_slice(&outputs[0][..serialized_size]);
+        swapped.extend_from_slice(&outputs[0][serialized_size * 2..]);
+
+        let mut inputs = outputs.iter()
+            .enumerate()
+            .map(|(id, output)| match id {
+                0 => Some(Cursor::new(swapped.as_slice())),
+                _ => Some(Cursor::new(output.as_slice()))
+            })
+            .collect::<Vec<_>>();
+
+        assert!(decode(&*codec, &mut inputs, &mut Vec::new(), size).is_err());
+    }
+}
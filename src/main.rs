@@ -2,33 +2,27 @@ extern crate rand;
 extern crate libc;
 extern crate nix;
 extern crate byteorder;
+extern crate crc32c;
 
 mod jerasurs;
 mod diskio;
+mod bench;
 
-use rand::Rng;
+use std::env;
+use std::process;
 
-fn main() {
-    let codec = jerasurs::codecs::liber8tion::create(6, 64);
-
-    let input: String = rand::thread_rng()
-        .gen_ascii_chars()
-        .take(24576)
-        .collect();
+static USAGE: &'static str = "usage: distriraid bench [--help] [options]";
 
-    let mut encoded = codec.encode(input.as_bytes());
-
-    encoded.erase_block(0, true);
-    encoded.erase_block(4, true);
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
 
-    let decoded = codec.decode(&mut encoded);
+    let result = match args.first().map(|arg| arg.as_str()) {
+        Some("bench") => bench::run(&args[1..]),
+        _ => Err(USAGE.to_string())
+    };
 
-    match decoded {
-        Some(data) => {
-            assert_eq!(data, input.as_bytes());
-        },
-        None => {
-            println!("omg");
-        }
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(2);
     }
 }